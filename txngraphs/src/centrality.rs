@@ -0,0 +1,460 @@
+use crate::summary::TransferSummary;
+use crate::types::TransferGraph;
+use alloy_primitives::Address;
+use petgraph::visit::EdgeRef;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// CentralityWeight
+///
+/// How much a link between two addresses counts for.
+/// - `Unweighted`: every pair of addresses with a transfer between them counts the same.
+/// - `Count`: by the number of transfers.
+/// - `Amount`: by the raw amount moved. Amounts of different tokens are added up as they are,
+///   so set `CentralityOptions::token` on graphs with more than one token.
+///
+/// For betweenness and closeness a heavier link is a shorter one, with length 1 / weight.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CentralityWeight {
+    Unweighted,
+    #[default]
+    Count,
+    Amount,
+}
+
+impl FromStr for CentralityWeight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "unweighted" => Ok(Self::Unweighted),
+            "count" | "transfers" => Ok(Self::Count),
+            "amount" => Ok(Self::Amount),
+            _ => Err(format!(
+                "unknown centrality weight {}, expected none, count or amount",
+                s
+            )),
+        }
+    }
+}
+
+/// CentralityOptions
+///
+/// Settings for `compute_centrality`. Personalized PageRank is only computed when `roots` is
+/// set, and restarts from them instead of from any address.
+#[derive(Debug, Clone)]
+pub struct CentralityOptions {
+    pub weight: CentralityWeight,
+    /// Only count transfers of this token.
+    pub token: Option<Address>,
+    /// Chance of following a transfer rather than restarting. Defaults to 0.85.
+    pub damping: f64,
+    pub roots: Vec<Address>,
+}
+
+impl Default for CentralityOptions {
+    fn default() -> Self {
+        Self {
+            weight: CentralityWeight::default(),
+            token: None,
+            damping: 0.85,
+            roots: Vec::new(),
+        }
+    }
+}
+
+impl CentralityOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_weight(self, weight: CentralityWeight) -> Self {
+        Self { weight, ..self }
+    }
+
+    pub fn with_token(self, token: Address) -> Self {
+        Self {
+            token: Some(token),
+            ..self
+        }
+    }
+
+    pub fn with_damping(self, damping: f64) -> Self {
+        Self { damping, ..self }
+    }
+
+    pub fn with_roots(self, roots: Vec<Address>) -> Self {
+        Self { roots, ..self }
+    }
+}
+
+/// CentralityScores
+///
+/// How central one address is by each measure.
+/// - `pagerank`: where a random walk along transfers spends its time. Sums to 1.
+/// - `personalized_pagerank`: the same, restarting from the roots, so it favours addresses
+///   the roots' funds are likely to reach.
+/// - `betweenness`: share of shortest paths between other addresses that pass through it,
+///   from 0 to 1.
+/// - `closeness`: harmonic closeness over outgoing paths, so addresses that can't reach each
+///   other are fine. From 0 to 1 on unweighted graphs.
+#[derive(Debug, Clone, Serialize)]
+pub struct CentralityScores {
+    pub address: Address,
+    pub pagerank: f64,
+    pub personalized_pagerank: Option<f64>,
+    pub betweenness: f64,
+    pub closeness: f64,
+}
+
+impl Display for CentralityScores {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.36} pagerank {:.6}", self.address, self.pagerank)?;
+        if let Some(personalized) = self.personalized_pagerank {
+            write!(f, ", personalized {:.6}", personalized)?;
+        }
+        writeln!(
+            f,
+            ", betweenness {:.6}, closeness {:.6}",
+            self.betweenness, self.closeness
+        )
+    }
+}
+
+// Addresses with one weighted link per (from, to) pair, self-transfers left out
+struct WeightedGraph {
+    addresses: Vec<Address>,
+    out: Vec<Vec<(usize, f64)>>,
+}
+
+impl WeightedGraph {
+    fn new(
+        addresses: Vec<Address>,
+        links: HashMap<(usize, usize), f64>,
+        weight: CentralityWeight,
+    ) -> Self {
+        let mut out = vec![Vec::new(); addresses.len()];
+        for ((from, to), total) in links {
+            if from != to && total > 0.0 {
+                let total = match weight {
+                    CentralityWeight::Unweighted => 1.0,
+                    _ => total,
+                };
+                out[from].push((to, total));
+            }
+        }
+        // HashMap order would otherwise leak into float sums
+        for links in &mut out {
+            links.sort_by_key(|(to, _)| *to);
+        }
+        Self { addresses, out }
+    }
+
+    fn len(&self) -> usize {
+        self.addresses.len()
+    }
+}
+
+/// Centrality of every address in `graph`, highest PageRank first.
+pub fn compute_centrality(
+    graph: &TransferGraph,
+    options: &CentralityOptions,
+) -> Vec<CentralityScores> {
+    let addresses = graph.node_weights().map(|node| node.address).collect();
+    let mut links: HashMap<(usize, usize), f64> = HashMap::new();
+    for edge in graph.edge_references() {
+        let transfer = edge.weight();
+        if options.token.is_some_and(|token| transfer.token != token) {
+            continue;
+        }
+        let weight = match options.weight {
+            CentralityWeight::Amount => f64::from(transfer.amount),
+            _ => 1.0,
+        };
+        *links
+            .entry((edge.source().index(), edge.target().index()))
+            .or_default() += weight;
+    }
+    scores(
+        &WeightedGraph::new(addresses, links, options.weight),
+        options,
+    )
+}
+
+/// Centrality over the aggregated summary graph. Same as `compute_centrality` on the graph
+/// the summary was built from, minus any addresses with no transfers at all.
+pub fn compute_summary_centrality(
+    summary: &TransferSummary,
+    options: &CentralityOptions,
+) -> Vec<CentralityScores> {
+    let graph = &summary.summary_graph;
+    let addresses = graph.node_weights().copied().collect();
+    let mut links: HashMap<(usize, usize), f64> = HashMap::new();
+    for edge in graph.edge_references() {
+        let weight: f64 = edge
+            .weight()
+            .tokens
+            .iter()
+            .filter(|amounts| options.token.is_none_or(|token| amounts.token == token))
+            .map(|amounts| match options.weight {
+                CentralityWeight::Amount => f64::from(amounts.total),
+                _ => amounts.no_transfers as f64,
+            })
+            .sum();
+        links.insert((edge.source().index(), edge.target().index()), weight);
+    }
+    scores(
+        &WeightedGraph::new(addresses, links, options.weight),
+        options,
+    )
+}
+
+fn scores(graph: &WeightedGraph, options: &CentralityOptions) -> Vec<CentralityScores> {
+    let uniform = vec![1.0 / graph.len() as f64; graph.len()];
+    let pagerank = page_rank(graph, &uniform, options.damping);
+
+    let root_indices: Vec<usize> = graph
+        .addresses
+        .iter()
+        .enumerate()
+        .filter(|(_, address)| options.roots.contains(address))
+        .map(|(i, _)| i)
+        .collect();
+    let personalized = (!root_indices.is_empty()).then(|| {
+        let mut restart = vec![0.0; graph.len()];
+        for i in &root_indices {
+            restart[*i] = 1.0 / root_indices.len() as f64;
+        }
+        page_rank(graph, &restart, options.damping)
+    });
+
+    let (betweenness, closeness) = shortest_path_scores(graph);
+
+    let mut scores: Vec<CentralityScores> = (0..graph.len())
+        .map(|i| CentralityScores {
+            address: graph.addresses[i],
+            pagerank: pagerank[i],
+            personalized_pagerank: personalized.as_ref().map(|scores| scores[i]),
+            betweenness: betweenness[i],
+            closeness: closeness[i],
+        })
+        .collect();
+    scores.sort_by(|a, b| {
+        b.pagerank
+            .total_cmp(&a.pagerank)
+            .then(a.address.cmp(&b.address))
+    });
+    scores
+}
+
+// Power iteration, restarting according to `restart`, which also takes up the rank of
+// addresses that never send anything
+fn page_rank(graph: &WeightedGraph, restart: &[f64], damping: f64) -> Vec<f64> {
+    const MAX_ITERATIONS: usize = 100;
+    const TOLERANCE: f64 = 1e-10;
+
+    let out_weight: Vec<f64> = graph
+        .out
+        .iter()
+        .map(|links| links.iter().map(|(_, weight)| weight).sum())
+        .collect();
+    let mut rank = restart.to_vec();
+
+    for _ in 0..MAX_ITERATIONS {
+        let dangling: f64 = (0..graph.len())
+            .filter(|i| graph.out[*i].is_empty())
+            .map(|i| rank[i])
+            .sum();
+        let mut next: Vec<f64> = restart
+            .iter()
+            .map(|r| (1.0 - damping + damping * dangling) * r)
+            .collect();
+        for (from, links) in graph.out.iter().enumerate() {
+            for (to, weight) in links {
+                next[*to] += damping * rank[from] * weight / out_weight[from];
+            }
+        }
+
+        let change: f64 = next.iter().zip(&rank).map(|(a, b)| (a - b).abs()).sum();
+        rank = next;
+        if change < TOLERANCE {
+            break;
+        }
+    }
+    rank
+}
+
+// Dijkstra queue entry, closest first
+struct Queued(f64, usize);
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.total_cmp(&self.0).then(other.1.cmp(&self.1))
+    }
+}
+
+// Brandes' betweenness and harmonic closeness, from one Dijkstra run per address
+fn shortest_path_scores(graph: &WeightedGraph) -> (Vec<f64>, Vec<f64>) {
+    let n = graph.len();
+    let per_source: Vec<(Vec<f64>, f64)> = (0..n)
+        .into_par_iter()
+        .map(|source| {
+            let mut distance = vec![f64::INFINITY; n];
+            let mut paths = vec![0.0; n];
+            let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); n];
+            let mut settled = Vec::with_capacity(n);
+            let mut done = vec![false; n];
+            let mut queue = BinaryHeap::new();
+
+            distance[source] = 0.0;
+            paths[source] = 1.0;
+            queue.push(Queued(0.0, source));
+            while let Some(Queued(dist, node)) = queue.pop() {
+                if done[node] {
+                    continue;
+                }
+                done[node] = true;
+                settled.push(node);
+                for (to, weight) in &graph.out[node] {
+                    let through = dist + 1.0 / weight;
+                    if through < distance[*to] {
+                        distance[*to] = through;
+                        paths[*to] = paths[node];
+                        predecessors[*to] = vec![node];
+                        queue.push(Queued(through, *to));
+                    } else if through == distance[*to] {
+                        paths[*to] += paths[node];
+                        predecessors[*to].push(node);
+                    }
+                }
+            }
+
+            let mut dependency = vec![0.0; n];
+            let mut betweenness = vec![0.0; n];
+            for node in settled.iter().rev() {
+                for from in &predecessors[*node] {
+                    dependency[*from] += paths[*from] / paths[*node] * (1.0 + dependency[*node]);
+                }
+                if *node != source {
+                    betweenness[*node] = dependency[*node];
+                }
+            }
+            let closeness = settled
+                .iter()
+                .filter(|node| **node != source)
+                .fold(0.0, |total, node| total + 1.0 / distance[*node]);
+            (betweenness, closeness)
+        })
+        .collect();
+
+    let mut betweenness = vec![0.0; n];
+    let mut closeness = vec![0.0; n];
+    for (source, (scores, harmonic)) in per_source.into_iter().enumerate() {
+        for (total, score) in betweenness.iter_mut().zip(scores) {
+            *total += score;
+        }
+        closeness[source] = harmonic;
+    }
+    if n > 2 {
+        let pairs = ((n - 1) * (n - 2)) as f64;
+        betweenness.iter_mut().for_each(|score| *score /= pairs);
+    }
+    if n > 1 {
+        closeness
+            .iter_mut()
+            .for_each(|score| *score /= (n - 1) as f64);
+    }
+    (betweenness, closeness)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn score(scores: &[CentralityScores], n: u8) -> &CentralityScores {
+        scores
+            .iter()
+            .find(|score| score.address == address(n))
+            .unwrap()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn star_hub_is_the_most_central() {
+        // 1 sends to and receives from each of 2, 3 and 4
+        let graph = graph_of(&[
+            transfer(1, 2, 1, 0, 10),
+            transfer(2, 1, 2, 0, 10),
+            transfer(1, 3, 3, 0, 10),
+            transfer(3, 1, 4, 0, 10),
+            transfer(1, 4, 5, 0, 10),
+            transfer(4, 1, 6, 0, 10),
+        ]);
+        let scores = compute_centrality(
+            &graph,
+            &CentralityOptions::new().with_roots(vec![address(2)]),
+        );
+
+        assert_eq!(scores[0].address, address(1));
+        // hub = 0.15 / 4 + 0.85 * (1 - hub), so hub = (0.25 + 0.75 * 0.85) / 1.85
+        assert!(close(scores[0].pagerank, 0.8875 / 1.85));
+        assert!(close(scores.iter().map(|score| score.pagerank).sum(), 1.0));
+        // Every path between two spokes goes through the hub
+        assert!(close(scores[0].betweenness, 1.0));
+        assert!(close(score(&scores, 2).betweenness, 0.0));
+        assert!(close(scores[0].closeness, 1.0));
+        assert!(close(score(&scores, 2).closeness, 2.0 / 3.0));
+
+        let personalized = |n| score(&scores, n).personalized_pagerank.unwrap();
+        assert!(personalized(2) > personalized(3));
+        assert!(close(personalized(3), personalized(4)));
+    }
+
+    #[test]
+    fn path_scores_follow_the_direction_of_transfers() {
+        // 1 -> 2 -> 3, with 1 -> 2 three times over
+        let graph = graph_of(&[
+            transfer(1, 2, 1, 0, 10),
+            transfer(1, 2, 1, 1, 10),
+            transfer(1, 2, 1, 2, 10),
+            transfer(2, 3, 2, 0, 10),
+        ]);
+        let scores = compute_centrality(&graph, &CentralityOptions::new());
+
+        let order: Vec<Address> = scores.iter().map(|score| score.address).collect();
+        assert_eq!(order, vec![address(3), address(2), address(1)]);
+        assert!(scores[0].personalized_pagerank.is_none());
+        // Only 1 -> 3 passes through 2, out of the 2 pairs 2 could be between
+        assert!(close(score(&scores, 2).betweenness, 0.5));
+        assert!(close(score(&scores, 3).closeness, 0.0));
+        // Three transfers make 1 -> 2 a third as long, which leaves 3 at 4/3
+        assert!(close(score(&scores, 1).closeness, (3.0 + 0.75) / 2.0));
+        let unweighted = compute_centrality(
+            &graph,
+            &CentralityOptions::new().with_weight(CentralityWeight::Unweighted),
+        );
+        assert!(close(score(&unweighted, 1).closeness, (1.0 + 0.5) / 2.0));
+    }
+}
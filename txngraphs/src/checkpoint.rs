@@ -0,0 +1,204 @@
+use crate::{
+    traversal::{HubCutoff, TemporalWindow, TruncatedNode},
+    types::{TransferEdge, TransferNode},
+};
+use alloy_primitives::{Address, aliases::BlockNumber};
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// TraversalParams
+///
+/// The inputs that determine what a traversal builds. A checkpoint can only be resumed by a
+/// traversal with identical params.
+///
+/// Expansion policies and observers aren't serializable and aren't checked, so resume with
+/// the same ones you started with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraversalParams {
+    pub root_address: Address,
+    pub block_start: BlockNumber,
+    pub block_end: BlockNumber,
+    pub token_addresses: Vec<Address>,
+    pub max_depth: usize,
+    pub temporal: Option<TemporalWindow>,
+    pub hub_cutoff: Option<HubCutoff>,
+}
+
+impl TraversalParams {
+    /// Fail with a description of the first differing field if `other` doesn't match.
+    pub fn ensure_matches(&self, other: &TraversalParams) -> Result<()> {
+        if self == other {
+            return Ok(());
+        }
+        let mismatch = if self.root_address != other.root_address {
+            format!("root {} vs {}", self.root_address, other.root_address)
+        } else if (self.block_start, self.block_end) != (other.block_start, other.block_end) {
+            format!(
+                "blocks {}..={} vs {}..={}",
+                self.block_start, self.block_end, other.block_start, other.block_end
+            )
+        } else if self.token_addresses != other.token_addresses {
+            format!(
+                "tokens {:?} vs {:?}",
+                self.token_addresses, other.token_addresses
+            )
+        } else if self.max_depth != other.max_depth {
+            format!("max depth {} vs {}", self.max_depth, other.max_depth)
+        } else {
+            "temporal or hub settings differ".to_string()
+        };
+        bail!(
+            "Checkpoint was taken with different parameters: {}",
+            mismatch
+        )
+    }
+}
+
+/// Checkpoint
+///
+/// Traversal state at a tier boundary: the graph so far, the next tier to expand and the
+/// bookkeeping needed to carry on exactly where the run stopped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub params: TraversalParams,
+    /// Depth of the addresses in `tier`.
+    pub next_depth: usize,
+    pub tier: Vec<Address>,
+    /// Graph nodes in index order.
+    pub nodes: Vec<TransferNode>,
+    /// Graph edges in index order, as (source index, target index, edge).
+    pub edges: Vec<(usize, usize, TransferEdge)>,
+    pub visited: Vec<Address>,
+    pub arrivals: Vec<(Address, (BlockNumber, u64))>,
+    pub truncated: Vec<TruncatedNode>,
+    pub expanded_counts: Vec<usize>,
+    pub expanded: Vec<Address>,
+    pub queries: usize,
+}
+
+impl Checkpoint {
+    /// Write the checkpoint as JSON. The file is written next to `path` and renamed into
+    /// place, so a crash mid-write leaves the previous checkpoint intact.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let json = serde_json::to_vec(self).context("Failed to serialize checkpoint")?;
+        fs::write(&tmp_path, json)
+            .with_context(|| format!("Failed to write checkpoint to {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to move checkpoint to {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = fs::read(path)
+            .with_context(|| format!("Failed to read checkpoint {}", path.display()))?;
+        serde_json::from_slice(&json)
+            .with_context(|| format!("Failed to parse checkpoint {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::{CancellationToken, TraversalObserver};
+    use crate::test_utils::*;
+    use crate::traversal::*;
+    use std::sync::Arc;
+
+    // 5 turns out to be a hub at depth 2, but only with the transfer counts learned at
+    // depths 0 and 1
+    fn source() -> MemorySource {
+        let mut transfers = vec![
+            transfer(1, 2, 1, 0, 5),
+            transfer(1, 3, 1, 1, 5),
+            transfer(2, 4, 2, 0, 5),
+            transfer(3, 5, 2, 1, 5),
+            transfer(3, 6, 2, 2, 5),
+            transfer(3, 7, 2, 3, 5),
+            transfer(4, 8, 3, 0, 5),
+            transfer(6, 9, 3, 1, 5),
+        ];
+        transfers.extend((10..17).map(|to| transfer(5, to, 4, to as u64, 5)));
+        MemorySource::new(transfers)
+    }
+
+    fn options() -> TraversalOptions {
+        TraversalOptions::new().with_hub_cutoff(HubCutoff::Learned {
+            multiplier: 2.0,
+            min_samples: 2,
+            floor: 1,
+        })
+    }
+
+    fn build(options: &TraversalOptions, max_depth: usize) -> Result<TraversalResult> {
+        build_transfer_graph_with_options(
+            &source(),
+            address(1),
+            0,
+            100,
+            &[TOKEN],
+            max_depth,
+            options,
+        )
+    }
+
+    // Run to the end of depth 0, checkpointing to `path`, and cancel
+    fn interrupt(path: &Path) {
+        let token = CancellationToken::new();
+        let observer: Arc<dyn TraversalObserver> = Arc::new(CancelAfterDepth {
+            depth: 0,
+            token: token.clone(),
+        });
+        let options = options()
+            .with_checkpoint(path, false)
+            .with_cancellation(token)
+            .with_observer(observer);
+        let result = build(&options, 3).unwrap();
+        assert_eq!(result.status, TraversalStatus::Cancelled);
+    }
+
+    #[test]
+    fn resumed_run_matches_an_uninterrupted_one() {
+        let path = temp_path("checkpoint-resume");
+        let uninterrupted = build(&options(), 3).unwrap();
+
+        interrupt(&path);
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        assert_eq!(checkpoint.next_depth, 1);
+        assert_eq!(checkpoint.tier, vec![address(2), address(3)]);
+
+        let resumed = build(&options().with_checkpoint(&path, true), 3).unwrap();
+        assert_eq!(resumed.status, TraversalStatus::Complete);
+        assert_eq!(snapshot(&resumed.graph), snapshot(&uninterrupted.graph));
+        assert_eq!(resumed.truncated.len(), 1);
+        assert_eq!(resumed.truncated[0].address, address(5));
+        assert_eq!(
+            resumed.truncated[0].reason,
+            uninterrupted.truncated[0].reason
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resume_rejects_different_params() {
+        let path = temp_path("checkpoint-mismatch");
+        interrupt(&path);
+
+        let err = build(&options().with_checkpoint(&path, true), 2)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("max depth 3 vs 2"), "{}", err);
+        let err = build(&TraversalOptions::new().with_checkpoint(&path, true), 3)
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("temporal or hub settings"),
+            "{}",
+            err
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
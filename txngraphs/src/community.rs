@@ -0,0 +1,510 @@
+use crate::centrality::CentralityWeight;
+use crate::summary::{TransferSummary, decimal};
+use crate::types::TransferGraph;
+use alloy_primitives::{Address, aliases::U256};
+use petgraph::visit::EdgeRef;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
+
+/// CommunityOptions
+///
+/// Settings for `detect_communities`. Links are weighted as for centrality, and a higher
+/// `resolution` gives more, smaller communities.
+#[derive(Debug, Clone)]
+pub struct CommunityOptions {
+    pub weight: CentralityWeight,
+    /// Only count transfers of this token.
+    pub token: Option<Address>,
+    /// Defaults to 1, plain modularity.
+    pub resolution: f64,
+}
+
+impl Default for CommunityOptions {
+    fn default() -> Self {
+        Self {
+            weight: CentralityWeight::default(),
+            token: None,
+            resolution: 1.0,
+        }
+    }
+}
+
+impl CommunityOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_weight(self, weight: CentralityWeight) -> Self {
+        Self { weight, ..self }
+    }
+
+    pub fn with_token(self, token: Address) -> Self {
+        Self {
+            token: Some(token),
+            ..self
+        }
+    }
+
+    pub fn with_resolution(self, resolution: f64) -> Self {
+        Self { resolution, ..self }
+    }
+}
+
+/// CommunityVolume
+///
+/// Raw amounts of one token moved inside a community, and between it and everyone else.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CommunityVolume {
+    pub token: Address,
+    #[serde(serialize_with = "decimal")]
+    pub internal: U256,
+    #[serde(serialize_with = "decimal")]
+    pub external: U256,
+}
+
+/// CommunityStats
+///
+/// One community: its members, sorted, and how much of its activity stays inside it.
+/// Transfers with both ends in the community are internal, those with one end are external.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommunityStats {
+    pub id: usize,
+    pub members: Vec<Address>,
+    pub internal_transfers: usize,
+    pub external_transfers: usize,
+    /// Sorted by token.
+    pub tokens: Vec<CommunityVolume>,
+}
+
+impl Display for CommunityStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Community {}: {} members, {} internal and {} external transfers",
+            self.id,
+            self.members.len(),
+            self.internal_transfers,
+            self.external_transfers
+        )?;
+        for volume in &self.tokens {
+            writeln!(
+                f,
+                "  {:.36}: {} internal, {} external",
+                volume.token, volume.internal, volume.external
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Communities
+///
+/// Result of `detect_communities`. Ids go from 0, largest community first.
+#[derive(Debug, Clone, Serialize)]
+pub struct Communities {
+    pub assignment: HashMap<Address, usize>,
+    pub communities: Vec<CommunityStats>,
+    pub modularity: f64,
+}
+
+impl Communities {
+    pub fn community_of(&self, address: &Address) -> Option<usize> {
+        self.assignment.get(address).copied()
+    }
+
+    /// Set `TransferNode::community` on the nodes of `graph`, so the DOT writers color them.
+    pub fn apply(&self, graph: &mut TransferGraph) {
+        for node in graph.node_weights_mut() {
+            node.community = self.community_of(&node.address);
+        }
+    }
+}
+
+// Undirected weighted graph for Louvain. Each link is stored on both ends, and `self_loops`
+// holds the weight inside a node once nodes stand for whole communities
+#[derive(Clone)]
+struct LouvainGraph {
+    links: Vec<Vec<(usize, f64)>>,
+    self_loops: Vec<f64>,
+}
+
+impl LouvainGraph {
+    fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    fn degree(&self, node: usize) -> f64 {
+        self.links[node]
+            .iter()
+            .map(|(_, weight)| weight)
+            .sum::<f64>()
+            + 2.0 * self.self_loops[node]
+    }
+
+    // Twice the total link weight
+    fn total_degree(&self) -> f64 {
+        (0..self.len()).map(|node| self.degree(node)).sum()
+    }
+
+    // Move nodes between communities while that raises modularity. Returns the community of
+    // each node, numbered from 0, and whether anything moved.
+    fn local_moves(&self, resolution: f64) -> (Vec<usize>, bool) {
+        let two_m = self.total_degree();
+        let degree: Vec<f64> = (0..self.len()).map(|node| self.degree(node)).collect();
+        let mut community: Vec<usize> = (0..self.len()).collect();
+        let mut total = degree.clone();
+        let mut moved = false;
+        if two_m == 0.0 {
+            return (community, moved);
+        }
+
+        loop {
+            let mut changed = false;
+            for node in 0..self.len() {
+                let current = community[node];
+                total[current] -= degree[node];
+
+                let mut to_community: BTreeMap<usize, f64> = BTreeMap::new();
+                for (other, weight) in &self.links[node] {
+                    *to_community.entry(community[*other]).or_default() += weight;
+                }
+                let gain =
+                    |c: usize, weight: f64| weight - resolution * total[c] * degree[node] / two_m;
+
+                let mut best = (
+                    current,
+                    gain(current, *to_community.get(&current).unwrap_or(&0.0)),
+                );
+                for (c, weight) in &to_community {
+                    let candidate = gain(*c, *weight);
+                    if candidate > best.1 + 1e-12 {
+                        best = (*c, candidate);
+                    }
+                }
+
+                total[best.0] += degree[node];
+                if best.0 != current {
+                    community[node] = best.0;
+                    changed = true;
+                    moved = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        (renumber(&community), moved)
+    }
+
+    // One node per community, links summed and internal weight kept as self-loops
+    fn aggregate(&self, community: &[usize]) -> Self {
+        let count = community.iter().max().map_or(0, |max| max + 1);
+        let mut self_loops = vec![0.0; count];
+        let mut between: Vec<BTreeMap<usize, f64>> = vec![BTreeMap::new(); count];
+        for node in 0..self.len() {
+            let c = community[node];
+            self_loops[c] += self.self_loops[node];
+            for (other, weight) in &self.links[node] {
+                let d = community[*other];
+                if c == d {
+                    // Seen from both ends
+                    self_loops[c] += weight / 2.0;
+                } else {
+                    *between[c].entry(d).or_default() += weight;
+                }
+            }
+        }
+        Self {
+            links: between
+                .into_iter()
+                .map(|links| links.into_iter().collect())
+                .collect(),
+            self_loops,
+        }
+    }
+}
+
+// Community ids from 0 in order of first appearance
+fn renumber(community: &[usize]) -> Vec<usize> {
+    let mut ids: HashMap<usize, usize> = HashMap::new();
+    community
+        .iter()
+        .map(|c| {
+            let next = ids.len();
+            *ids.entry(*c).or_insert(next)
+        })
+        .collect()
+}
+
+/// Split the addresses of `summary` into communities with the Louvain method, treating
+/// transfers as undirected links, and work out each community's statistics.
+///
+/// Self-transfers are left out of the partition but count as internal transfers.
+pub fn detect_communities(summary: &TransferSummary, options: &CommunityOptions) -> Communities {
+    let graph = &summary.summary_graph;
+    let addresses: Vec<Address> = graph.node_weights().copied().collect();
+
+    let mut pairs: BTreeMap<(usize, usize), f64> = BTreeMap::new();
+    for edge in graph.edge_references() {
+        let (from, to) = (edge.source().index(), edge.target().index());
+        if from == to {
+            continue;
+        }
+        let weight: f64 = edge
+            .weight()
+            .tokens
+            .iter()
+            .filter(|amounts| options.token.is_none_or(|token| amounts.token == token))
+            .map(|amounts| match options.weight {
+                CentralityWeight::Amount => f64::from(amounts.total),
+                _ => amounts.no_transfers as f64,
+            })
+            .sum();
+        *pairs.entry((from.min(to), from.max(to))).or_default() += weight;
+    }
+
+    let mut links = vec![Vec::new(); addresses.len()];
+    for ((a, b), weight) in pairs {
+        if weight > 0.0 {
+            let weight = match options.weight {
+                CentralityWeight::Unweighted => 1.0,
+                _ => weight,
+            };
+            links[a].push((b, weight));
+            links[b].push((a, weight));
+        }
+    }
+    let original = LouvainGraph {
+        links,
+        self_loops: vec![0.0; addresses.len()],
+    };
+
+    // Each level's communities become the next level's nodes
+    let mut membership: Vec<usize> = (0..addresses.len()).collect();
+    let mut level = original.clone();
+    loop {
+        let (community, moved) = level.local_moves(options.resolution);
+        if !moved {
+            break;
+        }
+        for c in &mut membership {
+            *c = community[*c];
+        }
+        level = level.aggregate(&community);
+    }
+
+    let modularity = modularity(&original, &membership, options.resolution);
+    let (assignment, communities) = community_stats(summary, &addresses, &membership);
+    Communities {
+        assignment,
+        communities,
+        modularity,
+    }
+}
+
+fn modularity(graph: &LouvainGraph, membership: &[usize], resolution: f64) -> f64 {
+    let two_m = graph.total_degree();
+    if two_m == 0.0 {
+        return 0.0;
+    }
+    let count = membership.iter().max().map_or(0, |max| max + 1);
+    let mut internal = vec![0.0; count];
+    let mut degree = vec![0.0; count];
+    for node in 0..graph.len() {
+        degree[membership[node]] += graph.degree(node);
+        for (other, weight) in &graph.links[node] {
+            if membership[*other] == membership[node] {
+                internal[membership[node]] += weight;
+            }
+        }
+    }
+    (0..count)
+        .map(|c| internal[c] / two_m - resolution * (degree[c] / two_m).powi(2))
+        .sum()
+}
+
+// Final ids, largest community first, and the statistics of each
+fn community_stats(
+    summary: &TransferSummary,
+    addresses: &[Address],
+    membership: &[usize],
+) -> (HashMap<Address, usize>, Vec<CommunityStats>) {
+    let count = membership.iter().max().map_or(0, |max| max + 1);
+    let mut members: Vec<Vec<Address>> = vec![Vec::new(); count];
+    for (node, c) in membership.iter().enumerate() {
+        members[*c].push(addresses[node]);
+    }
+    for group in &mut members {
+        group.sort();
+    }
+    members.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+    let assignment: HashMap<Address, usize> = members
+        .iter()
+        .enumerate()
+        .flat_map(|(id, group)| group.iter().map(move |address| (*address, id)))
+        .collect();
+
+    let mut communities: Vec<CommunityStats> = members
+        .into_iter()
+        .enumerate()
+        .map(|(id, members)| CommunityStats {
+            id,
+            members,
+            internal_transfers: 0,
+            external_transfers: 0,
+            tokens: Vec::new(),
+        })
+        .collect();
+    let mut volumes: Vec<BTreeMap<Address, CommunityVolume>> = vec![BTreeMap::new(); count];
+
+    let graph = &summary.summary_graph;
+    for edge in graph.edge_references() {
+        let from = assignment[&graph[edge.source()]];
+        let to = assignment[&graph[edge.target()]];
+        let internal = from == to;
+        let ends: &[usize] = if internal { &[from] } else { &[from, to] };
+        for c in ends {
+            let stats = &mut communities[*c];
+            if internal {
+                stats.internal_transfers += edge.weight().no_transfers;
+            } else {
+                stats.external_transfers += edge.weight().no_transfers;
+            }
+            for amounts in &edge.weight().tokens {
+                let volume = volumes[*c]
+                    .entry(amounts.token)
+                    .or_insert_with(|| CommunityVolume {
+                        token: amounts.token,
+                        ..CommunityVolume::default()
+                    });
+                if internal {
+                    volume.internal = volume.internal.saturating_add(amounts.total);
+                } else {
+                    volume.external = volume.external.saturating_add(amounts.total);
+                }
+            }
+        }
+    }
+    for (stats, volumes) in communities.iter_mut().zip(volumes) {
+        stats.tokens = volumes.into_values().collect();
+    }
+
+    (assignment, communities)
+}
+
+/// Core number of every address in `summary`: the largest k for which it is in the k-core,
+/// the part of the graph where every address has at least k distinct counterparties.
+pub fn core_numbers(summary: &TransferSummary) -> HashMap<Address, usize> {
+    let graph = &summary.summary_graph;
+    let mut neighbors: Vec<HashSet<usize>> = vec![HashSet::new(); graph.node_count()];
+    for edge in graph.edge_references() {
+        let (from, to) = (edge.source().index(), edge.target().index());
+        if from != to {
+            neighbors[from].insert(to);
+            neighbors[to].insert(from);
+        }
+    }
+
+    // Peel the address with the fewest remaining counterparties, lowest index first
+    let mut degree: Vec<usize> = neighbors.iter().map(HashSet::len).collect();
+    let mut queue: BTreeSet<(usize, usize)> = degree
+        .iter()
+        .enumerate()
+        .map(|(node, d)| (*d, node))
+        .collect();
+    let mut core = vec![0; graph.node_count()];
+    let mut removed = vec![false; graph.node_count()];
+    let mut k = 0;
+    while let Some((d, node)) = queue.pop_first() {
+        k = k.max(d);
+        core[node] = k;
+        removed[node] = true;
+        for other in &neighbors[node] {
+            if !removed[*other] {
+                queue.remove(&(degree[*other], *other));
+                degree[*other] -= 1;
+                queue.insert((degree[*other], *other));
+            }
+        }
+    }
+
+    graph
+        .node_indices()
+        .map(|idx| (graph[idx], core[idx.index()]))
+        .collect()
+}
+
+/// Addresses in the k-core of `summary`, sorted.
+pub fn k_core(summary: &TransferSummary, k: usize) -> Vec<Address> {
+    let mut members: Vec<Address> = core_numbers(summary)
+        .into_iter()
+        .filter(|(_, core)| *core >= k)
+        .map(|(address, _)| address)
+        .collect();
+    members.sort();
+    members
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    // Cliques 1-4 and 5-8, one transfer per pair, joined by 4 -> 5, with 9 hanging off 1
+    fn two_cliques() -> TransferGraph {
+        let mut transfers = Vec::new();
+        for clique in [[1, 2, 3, 4], [5, 6, 7, 8]] {
+            for (i, from) in clique.iter().enumerate() {
+                for to in &clique[i + 1..] {
+                    transfers.push(transfer(*from, *to, transfers.len() as u64, 0, 10));
+                }
+            }
+        }
+        transfers.push(transfer(4, 5, 100, 0, 10));
+        transfers.push(transfer(1, 9, 101, 0, 10));
+        graph_of(&transfers)
+    }
+
+    #[test]
+    fn louvain_splits_joined_cliques() {
+        let mut graph = two_cliques();
+        let summary = TransferSummary::from_transfer_graph(&graph);
+        let communities = detect_communities(&summary, &CommunityOptions::new());
+
+        assert_eq!(communities.communities.len(), 2);
+        let first = communities.community_of(&address(1)).unwrap();
+        let second = communities.community_of(&address(5)).unwrap();
+        assert_ne!(first, second);
+        for n in [2, 3, 4, 9] {
+            assert_eq!(communities.community_of(&address(n)), Some(first));
+        }
+        for n in [6, 7, 8] {
+            assert_eq!(communities.community_of(&address(n)), Some(second));
+        }
+        assert!(communities.modularity > 0.3);
+
+        // Only the bridge crosses, so each side has one external transfer
+        let stats = &communities.communities[first];
+        assert_eq!((stats.internal_transfers, stats.external_transfers), (7, 1));
+
+        communities.apply(&mut graph);
+        assert_eq!(graph.node(&address(9)).unwrap().community, Some(first));
+    }
+
+    #[test]
+    fn k_core_drops_loosely_connected_addresses() {
+        let summary = TransferSummary::from_transfer_graph(&two_cliques());
+        let cores = core_numbers(&summary);
+
+        assert_eq!(cores[&address(9)], 1);
+        assert_eq!(cores[&address(4)], 3);
+        assert_eq!(
+            k_core(&summary, 2),
+            (1..=8).map(address).collect::<Vec<_>>()
+        );
+        assert!(k_core(&summary, 4).is_empty());
+    }
+}
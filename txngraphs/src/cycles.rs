@@ -0,0 +1,356 @@
+use crate::summary::decimal;
+use crate::types::TransferGraph;
+use alloy_primitives::{
+    Address,
+    aliases::{BlockNumber, U256},
+};
+use petgraph::algo::tarjan_scc;
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use petgraph::visit::EdgeRef;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+/// CycleOptions
+///
+/// Bounds for `find_cycles`. The number of elementary cycles grows exponentially with their
+/// length, so keep `max_length` small on busy graphs and use `max_cycles` as a backstop.
+#[derive(Debug, Clone)]
+pub struct CycleOptions {
+    /// Longest cycle to look for, in hops. Defaults to 4.
+    pub max_length: usize,
+    /// Stop after finding this many cycles.
+    pub max_cycles: Option<usize>,
+    /// Only follow transfers of this token.
+    pub token: Option<Address>,
+}
+
+impl Default for CycleOptions {
+    fn default() -> Self {
+        Self {
+            max_length: 4,
+            max_cycles: None,
+            token: None,
+        }
+    }
+}
+
+impl CycleOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_length(self, max_length: usize) -> Self {
+        Self { max_length, ..self }
+    }
+
+    pub fn with_max_cycles(self, max_cycles: usize) -> Self {
+        Self {
+            max_cycles: Some(max_cycles),
+            ..self
+        }
+    }
+
+    pub fn with_token(self, token: Address) -> Self {
+        Self {
+            token: Some(token),
+            ..self
+        }
+    }
+}
+
+/// CycleVolume
+///
+/// The most of one token that could have gone all the way round a cycle: the smallest total
+/// of that token on any of its hops, in raw units.
+#[derive(Debug, Clone, Serialize)]
+pub struct CycleVolume {
+    pub token: Address,
+    #[serde(serialize_with = "decimal")]
+    pub volume: U256,
+}
+
+/// TransferCycle
+///
+/// An elementary cycle of addresses, each paying the next and the last paying the first.
+/// `addresses` starts from the one added to the graph first, usually the closest to the root.
+///
+/// `time_ordered` holds one transfer per hop, in order, each later than the one before, if
+/// such a sequence exists. That is what separates funds actually going round from addresses
+/// that merely happen to be connected in a loop.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferCycle {
+    pub addresses: Vec<Address>,
+    pub no_transfers: usize,
+    /// Tokens moved on every hop, sorted by token.
+    pub tokens: Vec<CycleVolume>,
+    pub first_block: BlockNumber,
+    pub last_block: BlockNumber,
+    pub time_ordered: Option<Vec<EdgeIndex>>,
+}
+
+impl TransferCycle {
+    /// Number of hops, which is also the number of addresses.
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Blocks between the first and last transfer on the cycle.
+    pub fn block_span(&self) -> BlockNumber {
+        self.last_block - self.first_block
+    }
+
+    pub fn is_time_ordered(&self) -> bool {
+        self.time_ordered.is_some()
+    }
+}
+
+impl Display for TransferCycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for address in &self.addresses {
+            write!(f, "{:.36} -> ", address)?;
+        }
+        if let Some(first) = self.addresses.first() {
+            write!(f, "{:.36}", first)?;
+        }
+        write!(
+            f,
+            ", {} transfers in blocks {}..={}",
+            self.no_transfers, self.first_block, self.last_block
+        )?;
+        for volume in &self.tokens {
+            write!(f, ", {} of {:.36}", volume.volume, volume.token)?;
+        }
+        if self.is_time_ordered() {
+            write!(f, ", time-ordered")?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Every elementary cycle of `graph` up to `options.max_length` hops, without self-transfers.
+///
+/// Time-ordered cycles come first, then shorter ones, then the earliest. Unlike
+/// `find_closed_loops`, which returns whole strongly connected components, each cycle here
+/// visits its addresses once.
+pub fn find_cycles(graph: &TransferGraph, options: &CycleOptions) -> Vec<TransferCycle> {
+    let keep = |idx: EdgeIndex| options.token.is_none_or(|token| graph[idx].token == token);
+
+    // Distinct recipients per node, lowest index first, so cycles come out in a stable order
+    let mut successors: Vec<Vec<NodeIndex>> = vec![Vec::new(); graph.node_count()];
+    for edge in graph.edge_references() {
+        if edge.source() != edge.target() && keep(edge.id()) {
+            successors[edge.source().index()].push(edge.target());
+        }
+    }
+    for recipients in &mut successors {
+        recipients.sort();
+        recipients.dedup();
+    }
+
+    // A cycle never leaves its strongly connected component
+    let mut component = vec![0; graph.node_count()];
+    for (i, scc) in tarjan_scc(graph.as_graph()).into_iter().enumerate() {
+        for idx in scc {
+            component[idx.index()] = i;
+        }
+    }
+
+    let mut found: Vec<Vec<NodeIndex>> = Vec::new();
+    let limit = options.max_cycles.unwrap_or(usize::MAX);
+    // Shared by every start, since the search clears each entry again as it backtracks
+    let mut on_path = vec![false; graph.node_count()];
+    for start in graph.node_indices() {
+        if found.len() >= limit {
+            break;
+        }
+        // Each cycle is found once, from its lowest index
+        let mut path = vec![start];
+        on_path[start.index()] = true;
+        let mut stack = vec![0];
+
+        while let Some(next) = stack.last_mut() {
+            let current = *path.last().unwrap();
+            let Some(&to) = successors[current.index()].get(*next) else {
+                stack.pop();
+                on_path[path.pop().unwrap().index()] = false;
+                continue;
+            };
+            *next += 1;
+
+            if to == start {
+                found.push(path.clone());
+                if found.len() >= limit {
+                    break;
+                }
+            } else if to > start
+                && !on_path[to.index()]
+                && component[to.index()] == component[start.index()]
+                && path.len() < options.max_length
+            {
+                path.push(to);
+                on_path[to.index()] = true;
+                stack.push(0);
+            }
+        }
+    }
+
+    let mut cycles: Vec<TransferCycle> = found
+        .into_iter()
+        .map(|nodes| describe_cycle(graph, &nodes, &keep))
+        .collect();
+    cycles.sort_by(|a, b| {
+        b.is_time_ordered()
+            .cmp(&a.is_time_ordered())
+            .then(a.len().cmp(&b.len()))
+            .then(a.first_block.cmp(&b.first_block))
+            .then_with(|| a.addresses.cmp(&b.addresses))
+    });
+    cycles
+}
+
+// Volumes, timing and a time-ordered witness for the cycle through `nodes`
+fn describe_cycle(
+    graph: &TransferGraph,
+    nodes: &[NodeIndex],
+    keep: &impl Fn(EdgeIndex) -> bool,
+) -> TransferCycle {
+    // The transfers on each hop, earliest first
+    let hops: Vec<Vec<EdgeIndex>> = (0..nodes.len())
+        .map(|i| {
+            let (from, to) = (nodes[i], nodes[(i + 1) % nodes.len()]);
+            let mut edges: Vec<EdgeIndex> = graph
+                .edges_connecting(from, to)
+                .map(|edge| edge.id())
+                .filter(|idx| keep(*idx))
+                .collect();
+            edges.sort_by_key(|idx| graph[*idx].position());
+            edges
+        })
+        .collect();
+
+    let mut totals: BTreeMap<Address, Vec<U256>> = BTreeMap::new();
+    for (i, edges) in hops.iter().enumerate() {
+        for idx in edges {
+            let hop_totals = totals
+                .entry(graph[*idx].token)
+                .or_insert_with(|| vec![U256::ZERO; hops.len()]);
+            hop_totals[i] = hop_totals[i].saturating_add(graph[*idx].amount);
+        }
+    }
+    let tokens = totals
+        .into_iter()
+        .filter_map(|(token, hop_totals)| {
+            let volume = hop_totals.into_iter().min().unwrap_or(U256::ZERO);
+            (!volume.is_zero()).then_some(CycleVolume { token, volume })
+        })
+        .collect();
+
+    let all = hops.iter().flatten().map(|idx| graph[*idx].block_number);
+    TransferCycle {
+        addresses: nodes.iter().map(|idx| graph[*idx].address).collect(),
+        no_transfers: hops.iter().map(Vec::len).sum(),
+        tokens,
+        first_block: all.clone().min().unwrap_or_default(),
+        last_block: all.max().unwrap_or_default(),
+        time_ordered: (0..hops.len()).find_map(|start| time_ordered_from(graph, &hops, start)),
+    }
+}
+
+// Earliest transfers going round from hop `start`, each strictly after the previous one.
+// Taking the earliest transfer that fits at every hop can only leave more room for the rest.
+fn time_ordered_from(
+    graph: &TransferGraph,
+    hops: &[Vec<EdgeIndex>],
+    start: usize,
+) -> Option<Vec<EdgeIndex>> {
+    let mut witness = Vec::with_capacity(hops.len());
+    let mut after = None;
+    for i in 0..hops.len() {
+        let edges = &hops[(start + i) % hops.len()];
+        let first = match after {
+            Some(after) => edges.partition_point(|idx| graph[*idx].position() <= after),
+            None => 0,
+        };
+        let idx = *edges.get(first)?;
+        after = Some(graph[idx].position());
+        witness.push(idx);
+    }
+    Some(witness)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    // 1 -> 2 -> 3 -> 1 in time order, 4 -> 5 -> 6 -> 4 backwards in time, 7 <-> 8 in time
+    // order and a self-transfer at 9
+    fn graph() -> TransferGraph {
+        graph_of(&[
+            transfer(1, 2, 10, 0, 100),
+            transfer(2, 3, 9, 0, 5),
+            transfer(2, 3, 11, 0, 80),
+            transfer(3, 1, 12, 0, 60),
+            transfer(4, 5, 3, 0, 10),
+            transfer(5, 6, 2, 0, 10),
+            transfer(6, 4, 1, 0, 10),
+            transfer(7, 8, 20, 0, 10),
+            transfer(8, 7, 21, 0, 10),
+            transfer(9, 9, 22, 0, 10),
+        ])
+    }
+
+    #[test]
+    fn time_ordered_cycles_come_first() {
+        let graph = graph();
+        let cycles = find_cycles(&graph, &CycleOptions::new());
+
+        let addresses: Vec<Vec<Address>> =
+            cycles.iter().map(|cycle| cycle.addresses.clone()).collect();
+        assert_eq!(
+            addresses,
+            vec![
+                vec![address(7), address(8)],
+                vec![address(1), address(2), address(3)],
+                vec![address(4), address(5), address(6)],
+            ]
+        );
+
+        let ordered = &cycles[1];
+        // The block 9 transfer on 2 -> 3 comes before the funds got there
+        assert_eq!(
+            ordered.time_ordered,
+            Some(vec![
+                EdgeIndex::new(0),
+                EdgeIndex::new(2),
+                EdgeIndex::new(3)
+            ])
+        );
+        assert_eq!(ordered.no_transfers, 4);
+        assert_eq!((ordered.first_block, ordered.last_block), (9, 12));
+        assert_eq!(ordered.tokens[0].volume, U256::from(60));
+
+        // Every rotation of 4 -> 5 -> 6 -> 4 goes back in time somewhere
+        assert!(!cycles[2].is_time_ordered());
+    }
+
+    #[test]
+    fn options_bound_the_search() {
+        let graph = graph();
+
+        let short = find_cycles(&graph, &CycleOptions::new().with_max_length(2));
+        assert_eq!(short.len(), 1);
+        assert_eq!(short[0].addresses, vec![address(7), address(8)]);
+
+        assert_eq!(
+            find_cycles(&graph, &CycleOptions::new().with_max_cycles(2)).len(),
+            2
+        );
+        assert!(find_cycles(&graph, &CycleOptions::new().with_token(Address::ZERO)).is_empty());
+    }
+}
@@ -22,7 +22,12 @@ use zerocopy::IntoBytes;
 ///
 /// A generic trait across different data sources.
 ///
-pub trait TransferDataSource {
+/// Sources must be `Sync` so that traversals can query several addresses at once.
+///
+/// An empty `token_addresses` means any token: every ERC-20 `Transfer` touching the address is
+/// returned, for when you don't know what the funds were swapped into.
+///
+pub trait TransferDataSource: Sync {
     fn get_transfers(
         &self,
         address: &Address,
@@ -30,6 +35,55 @@ pub trait TransferDataSource {
         block_start: &BlockNumber,
         block_end: &BlockNumber,
     ) -> anyhow::Result<Vec<Transfer>>;
+
+    /// What `get_transfers` would return, or `None` if more than `limit` transfers match. Used
+    /// to spot hubs without paying for all of their transfers, so sources that can stop
+    /// scanning early should override this.
+    fn get_transfers_up_to(
+        &self,
+        address: &Address,
+        token_addresses: &[Address],
+        block_start: &BlockNumber,
+        block_end: &BlockNumber,
+        limit: usize,
+    ) -> anyhow::Result<Option<Vec<Transfer>>> {
+        let transfers = self.get_transfers(address, token_addresses, block_start, block_end)?;
+        Ok((transfers.len() <= limit).then_some(transfers))
+    }
+
+    /// Whether `address` has contract code. Sources that can't tell report `false`.
+    fn is_contract(&self, _address: &Address) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// Every transfer of `token_addresses` in the block range, whoever sent it. Used to build
+    /// a token's whole transfer graph when there's no root address.
+    fn get_token_transfers(
+        &self,
+        _token_addresses: &[Address],
+        _block_start: &BlockNumber,
+        _block_end: &BlockNumber,
+    ) -> anyhow::Result<Vec<Transfer>> {
+        anyhow::bail!("This data source can't scan a token's transfers without an address")
+    }
+
+    /// Every transfer of `token_addresses` emitted by one transaction, in log order. Used to
+    /// seed a traversal from an exploit transaction rather than an address.
+    fn get_transaction_transfers(
+        &self,
+        tx_hash: &B256,
+        _token_addresses: &[Address],
+    ) -> anyhow::Result<Vec<Transfer>> {
+        anyhow::bail!(
+            "This data source can't look up transaction {} by hash",
+            tx_hash
+        )
+    }
+}
+
+/// Whether `token` is selected by `token_addresses`, where an empty list selects every token.
+pub fn token_matches(token_addresses: &[Address], token: &Address) -> bool {
+    token_addresses.is_empty() || token_addresses.contains(token)
 }
 
 /// DuneDexTradesDataSource
@@ -52,6 +106,9 @@ pub trait TransferDataSource {
 ///
 /// For reference, see dune.com/queries/5488226.
 ///
+/// dex.trades has no log index, so every transfer gets `log_index` 0 and temporal traversal
+/// can only order trades by block.
+///
 pub struct DuneDexTradesDataSource {
     pub dex_trades: polars::prelude::DataFrame,
 }
@@ -71,24 +128,22 @@ impl TransferDataSource for DuneDexTradesDataSource {
         block_end: &BlockNumber,
     ) -> anyhow::Result<Vec<Transfer>> {
         let addr_hex = format!("{address:#x}");
-        let token_hex = format!("{:#x}", token_addresses[0]);
 
-        let filtered_trades = self
-            .dex_trades
-            .clone()
-            .lazy()
-            .filter(
-                col("tx_from")
-                    .eq(lit(addr_hex))
-                    .and(
-                        col("token_sold_address")
-                            .eq(lit(token_hex.clone()))
-                            .or(col("token_bought_address").eq(lit(token_hex))),
-                    )
-                    .and(col("block_number").gt_eq(lit(*block_start)))
-                    .and(col("block_number").lt_eq(lit(*block_end))),
-            )
-            .collect()?;
+        let mut filter = col("tx_from")
+            .eq(lit(addr_hex))
+            .and(col("block_number").gt_eq(lit(*block_start)))
+            .and(col("block_number").lt_eq(lit(*block_end)));
+        // No token filter in any-token mode
+        if let Some(token) = token_addresses.first() {
+            let token_hex = format!("{:#x}", token);
+            filter = filter.and(
+                col("token_sold_address")
+                    .eq(lit(token_hex.clone()))
+                    .or(col("token_bought_address").eq(lit(token_hex))),
+            );
+        }
+
+        let filtered_trades = self.dex_trades.clone().lazy().filter(filter).collect()?;
 
         info!("filtered_trades.height(): {}", filtered_trades.height());
 
@@ -112,6 +167,7 @@ impl TransferDataSource for DuneDexTradesDataSource {
                     col_block_number
                         .get(row)
                         .with_context(|| format!("Failed to get block_number for {}", row))?,
+                    0,
                     Address::from_str(
                         col_from_address
                             .get(row)
@@ -178,6 +234,7 @@ impl CryoTransferDataSource {
         // Extract columns from the DataFrame - using Cryo's actual schema
         let col_tx_hash = df.column("transaction_hash")?.binary()?;
         let col_block_number = df.column("block_number")?.u32()?;
+        let col_log_index = df.column("log_index")?.u32()?;
         let col_from_address = df.column("from_address")?.binary()?;
         let col_to_address = df.column("to_address")?.binary()?;
         let col_erc20 = df.column("erc20")?.binary()?;
@@ -211,6 +268,10 @@ impl CryoTransferDataSource {
                     .get(row)
                     .with_context(|| format!("Failed to get block_number for row {}", row))?
                     as u64,
+                col_log_index
+                    .get(row)
+                    .with_context(|| format!("Failed to get log_index for row {}", row))?
+                    as u64,
                 from_address,
                 to_address,
                 Address::from_slice(erc20_bytes),
@@ -243,12 +304,15 @@ impl TransferDataSource for CryoTransferDataSource {
                         *block_start as u64,
                         *block_end as u64,
                     )]),
-                    contracts: Some(vec![AddressChunk::Values(vec![
-                        token_addresses
-                            .iter()
-                            .flat_map(|address| address.as_bytes().to_vec())
-                            .collect(),
-                    ])]),
+                    // Leaving contracts unset fetches every token's transfers
+                    contracts: (!token_addresses.is_empty()).then(|| {
+                        vec![AddressChunk::Values(vec![
+                            token_addresses
+                                .iter()
+                                .flat_map(|address| address.as_bytes().to_vec())
+                                .collect(),
+                        ])]
+                    }),
                     ..Default::default()
                 }],
                 schemas: {
@@ -0,0 +1,283 @@
+use crate::summary::{SummaryFormat, TransferSummary, decimal, write_export};
+use crate::types::TransferGraph;
+use alloy_primitives::{Address, aliases::U256};
+use anyhow::Result;
+use petgraph::visit::EdgeRef;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::io::Write;
+
+/// TokenDegree
+///
+/// Raw amounts of one token an address received and sent.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TokenDegree {
+    pub token: Address,
+    #[serde(serialize_with = "decimal")]
+    pub in_amount: U256,
+    #[serde(serialize_with = "decimal")]
+    pub out_amount: U256,
+}
+
+/// DegreeStats
+///
+/// How connected one address is. Degrees count distinct counterparties, transfers count every
+/// transfer, and amounts are kept per token, sorted by token.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DegreeStats {
+    pub address: Address,
+    pub in_degree: usize,
+    pub out_degree: usize,
+    pub total_degree: usize,
+    pub in_transfers: usize,
+    pub out_transfers: usize,
+    pub total_transfers: usize,
+    pub tokens: Vec<TokenDegree>,
+}
+
+impl DegreeStats {
+    fn new(address: Address) -> Self {
+        Self {
+            address,
+            ..Self::default()
+        }
+    }
+}
+
+impl Display for DegreeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:.36} in {} ({} transfers), out {} ({} transfers)",
+            self.address, self.in_degree, self.in_transfers, self.out_degree, self.out_transfers
+        )
+    }
+}
+
+/// DegreeDistribution
+///
+/// One line of the "strandedness" table: the share of addresses with at least `min_transfers`
+/// transfers in or out.
+#[derive(Debug, Clone, Serialize)]
+pub struct DegreeDistribution {
+    pub min_transfers: usize,
+    pub no_nodes: usize,
+    pub percent_of_nodes: f64,
+}
+
+impl Display for DegreeDistribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            ">= {} transfers: {} nodes ({:.2}%)",
+            self.min_transfers, self.no_nodes, self.percent_of_nodes
+        )
+    }
+}
+
+// Accumulates degree stats from (from, to, token, transfers, amount) groups
+#[derive(Default)]
+struct DegreeAccumulator {
+    stats: HashMap<Address, DegreeStats>,
+    counterparties: HashSet<(Address, Address)>,
+    amounts: HashMap<Address, BTreeMap<Address, TokenDegree>>,
+}
+
+impl DegreeAccumulator {
+    fn add_node(&mut self, address: Address) {
+        self.stats
+            .entry(address)
+            .or_insert_with(|| DegreeStats::new(address));
+    }
+
+    fn add(&mut self, from: Address, to: Address, token: Address, transfers: usize, amount: U256) {
+        self.add_node(from);
+        self.add_node(to);
+        let new_pair = self.counterparties.insert((from, to));
+
+        let sender = self.stats.get_mut(&from).unwrap();
+        sender.out_transfers += transfers;
+        sender.out_degree += usize::from(new_pair);
+        let sent = self
+            .amounts
+            .entry(from)
+            .or_default()
+            .entry(token)
+            .or_insert_with(|| TokenDegree {
+                token,
+                ..TokenDegree::default()
+            });
+        sent.out_amount = sent.out_amount.saturating_add(amount);
+
+        let recipient = self.stats.get_mut(&to).unwrap();
+        recipient.in_transfers += transfers;
+        recipient.in_degree += usize::from(new_pair);
+        let received = self
+            .amounts
+            .entry(to)
+            .or_default()
+            .entry(token)
+            .or_insert_with(|| TokenDegree {
+                token,
+                ..TokenDegree::default()
+            });
+        received.in_amount = received.in_amount.saturating_add(amount);
+    }
+
+    // Most transfers first, then by address
+    fn finish(mut self) -> Vec<DegreeStats> {
+        let mut stats: Vec<DegreeStats> = self
+            .stats
+            .into_values()
+            .map(|mut node| {
+                node.total_degree = node.in_degree + node.out_degree;
+                node.total_transfers = node.in_transfers + node.out_transfers;
+                if let Some(amounts) = self.amounts.remove(&node.address) {
+                    node.tokens = amounts.into_values().collect();
+                }
+                node
+            })
+            .collect();
+        stats.sort_by(|a, b| {
+            b.total_transfers
+                .cmp(&a.total_transfers)
+                .then_with(|| a.address.cmp(&b.address))
+        });
+        stats
+    }
+}
+
+/// Degree stats for every address in `graph`, most transfers first.
+///
+/// A self-transfer counts towards both the in and out side of its address.
+pub fn compute_degree_stats(graph: &TransferGraph) -> Vec<DegreeStats> {
+    let mut acc = DegreeAccumulator::default();
+    for node in graph.node_weights() {
+        acc.add_node(node.address);
+    }
+    for edge in graph.edge_references() {
+        let transfer = edge.weight();
+        acc.add(
+            graph[edge.source()].address,
+            graph[edge.target()].address,
+            transfer.token,
+            1,
+            transfer.amount,
+        );
+    }
+    acc.finish()
+}
+
+/// Degree stats from the aggregated summary graph. Same as `compute_degree_stats` on the graph
+/// the summary was built from, minus any addresses with no transfers at all.
+pub fn compute_summary_degree_stats(summary: &TransferSummary) -> Vec<DegreeStats> {
+    let graph = &summary.summary_graph;
+    let mut acc = DegreeAccumulator::default();
+    for edge in graph.edge_references() {
+        for amounts in &edge.weight().tokens {
+            acc.add(
+                graph[edge.source()],
+                graph[edge.target()],
+                amounts.token,
+                amounts.no_transfers,
+                amounts.total,
+            );
+        }
+    }
+    acc.finish()
+}
+
+/// The "% of nodes with >= N transfers" table, with a line for every transfer count some
+/// address has, from the smallest up.
+pub fn degree_distribution(stats: &[DegreeStats]) -> Vec<DegreeDistribution> {
+    let mut counts: Vec<usize> = stats.iter().map(|node| node.total_transfers).collect();
+    counts.sort_unstable();
+
+    let mut distribution = Vec::new();
+    let mut start = 0;
+    while start < counts.len() {
+        let min_transfers = counts[start];
+        let no_nodes = counts.len() - start;
+        distribution.push(DegreeDistribution {
+            min_transfers,
+            no_nodes,
+            percent_of_nodes: no_nodes as f64 * 100.0 / counts.len() as f64,
+        });
+        start += counts[start..].partition_point(|count| *count == min_transfers);
+    }
+    distribution
+}
+
+const STATS_COLUMNS: [&str; 10] = [
+    "address",
+    "in_degree",
+    "out_degree",
+    "total_degree",
+    "in_transfers",
+    "out_transfers",
+    "total_transfers",
+    "token",
+    "in_amount",
+    "out_amount",
+];
+
+/// Write per-address degree stats to `out`. CSV and Markdown get a line per (address, token).
+pub fn write_degree_stats(
+    stats: &[DegreeStats],
+    format: SummaryFormat,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut lines = Vec::new();
+    for node in stats {
+        let counts = [
+            node.address.to_string(),
+            node.in_degree.to_string(),
+            node.out_degree.to_string(),
+            node.total_degree.to_string(),
+            node.in_transfers.to_string(),
+            node.out_transfers.to_string(),
+            node.total_transfers.to_string(),
+        ];
+        if node.tokens.is_empty() {
+            let mut line = counts.to_vec();
+            line.extend([String::new(), "0".to_string(), "0".to_string()]);
+            lines.push(line);
+        }
+        for amounts in &node.tokens {
+            let mut line = counts.to_vec();
+            line.extend([
+                amounts.token.to_string(),
+                amounts.in_amount.to_string(),
+                amounts.out_amount.to_string(),
+            ]);
+            lines.push(line);
+        }
+    }
+    write_export(stats, &STATS_COLUMNS, lines, format, out)
+}
+
+/// Write the degree distribution table to `out`.
+pub fn write_degree_distribution(
+    distribution: &[DegreeDistribution],
+    format: SummaryFormat,
+    out: &mut impl Write,
+) -> Result<()> {
+    let lines = distribution
+        .iter()
+        .map(|line| {
+            vec![
+                line.min_transfers.to_string(),
+                line.no_nodes.to_string(),
+                format!("{:.2}", line.percent_of_nodes),
+            ]
+        })
+        .collect();
+    write_export(
+        distribution,
+        &["min_transfers", "no_nodes", "percent_of_nodes"],
+        lines,
+        format,
+        out,
+    )
+}
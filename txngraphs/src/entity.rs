@@ -0,0 +1,517 @@
+use crate::types::{NodeFlags, TransferGraph, TransferNode};
+use alloy_primitives::{Address, aliases::TxHash};
+use petgraph::Direction;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
+
+/// ClusterHeuristic
+///
+/// Reasons to believe two addresses belong to the same actor.
+/// - `CommonFunder`: both got their first transfer in the graph from the same address, which
+///   funded only a few addresses that way, e.g. burner wallets topped up from one source.
+/// - `DepositForwarding`: a deposit address that many senders pay and that forwards
+///   everything to one consolidation wallet, along with other deposit addresses doing the
+///   same, belongs to that wallet's owner.
+/// - `CoSpending`: both sent transfers in the same transaction, repeatedly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum ClusterHeuristic {
+    CommonFunder,
+    DepositForwarding,
+    CoSpending,
+}
+
+impl Display for ClusterHeuristic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CommonFunder => write!(f, "common funder"),
+            Self::DepositForwarding => write!(f, "deposit forwarding"),
+            Self::CoSpending => write!(f, "co-spending"),
+        }
+    }
+}
+
+/// ClusterOptions
+///
+/// Which heuristics `cluster_addresses` applies, and how much evidence each needs. Hubs are
+/// never treated as funders, deposit addresses or consolidation wallets, and neither hubs nor
+/// contracts count as co-spenders, since exchanges and DEX pools would otherwise pull everyone
+/// they touch into one entity.
+#[derive(Debug, Clone)]
+pub struct ClusterOptions {
+    /// Largest number of addresses one funder can first-fund and still count. 0 turns the
+    /// heuristic off. Defaults to 10.
+    pub max_funded: usize,
+    /// Distinct senders a deposit address needs, and deposit addresses a consolidation
+    /// wallet needs. 0 turns the heuristic off. Defaults to 2.
+    pub min_deposit_senders: usize,
+    /// Transactions two addresses must both send in. 0 turns the heuristic off. Defaults
+    /// to 2.
+    pub min_co_spends: usize,
+    /// Transactions with more senders than this, e.g. aggregator batches, are left out of
+    /// co-spending. Defaults to 8.
+    pub max_co_spenders: usize,
+}
+
+impl Default for ClusterOptions {
+    fn default() -> Self {
+        Self {
+            max_funded: 10,
+            min_deposit_senders: 2,
+            min_co_spends: 2,
+            max_co_spenders: 8,
+        }
+    }
+}
+
+impl ClusterOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_funded(self, max_funded: usize) -> Self {
+        Self { max_funded, ..self }
+    }
+
+    pub fn with_min_deposit_senders(self, min_deposit_senders: usize) -> Self {
+        Self {
+            min_deposit_senders,
+            ..self
+        }
+    }
+
+    pub fn with_min_co_spends(self, min_co_spends: usize) -> Self {
+        Self {
+            min_co_spends,
+            ..self
+        }
+    }
+
+    pub fn with_max_co_spenders(self, max_co_spenders: usize) -> Self {
+        Self {
+            max_co_spenders,
+            ..self
+        }
+    }
+}
+
+/// Entity
+///
+/// Addresses believed to be controlled by one actor. `id` is the member added to the graph
+/// first, usually the closest to the root, and `members` are sorted.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entity {
+    pub id: Address,
+    pub members: Vec<Address>,
+    /// Heuristics that linked the members, sorted.
+    pub heuristics: Vec<ClusterHeuristic>,
+}
+
+impl Display for Entity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let heuristics: Vec<String> = self.heuristics.iter().map(|h| h.to_string()).collect();
+        writeln!(
+            f,
+            "Entity {:.36}: {} addresses by {}",
+            self.id,
+            self.members.len(),
+            heuristics.join(", ")
+        )?;
+        for member in &self.members {
+            writeln!(f, "  {}", member)?;
+        }
+        Ok(())
+    }
+}
+
+/// EntityMap
+///
+/// The entity of every address in a graph. `entities` only lists entities of more than one
+/// address, largest first, while `entity_of` covers every address.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EntityMap {
+    /// Entity id of every address in the graph, including single-address entities.
+    pub assignment: HashMap<Address, Address>,
+    pub entities: Vec<Entity>,
+}
+
+impl EntityMap {
+    /// Entity id of `address`, which is the address itself if it wasn't clustered.
+    pub fn entity_of(&self, address: &Address) -> Address {
+        self.assignment.get(address).copied().unwrap_or(*address)
+    }
+
+    /// Collapse `graph` into one node per entity, keyed by the entity id. Transfers keep
+    /// their details, and those between members of one entity become self-transfers.
+    ///
+    /// Each entity node takes the smallest depth of its members, their first root, and the
+    /// union of their flags, and is labeled with its size if it has more than one member.
+    pub fn collapse(&self, graph: &TransferGraph) -> TransferGraph {
+        let mut merged: BTreeMap<NodeIndex, TransferNode> = BTreeMap::new();
+        let mut first: HashMap<Address, NodeIndex> = HashMap::new();
+        let mut sizes: HashMap<Address, usize> = HashMap::new();
+        for idx in graph.node_indices() {
+            let member = &graph[idx];
+            let id = self.entity_of(&member.address);
+            *sizes.entry(id).or_default() += 1;
+            let slot = *first.entry(id).or_insert(idx);
+            let node = merged.entry(slot).or_insert_with(|| TransferNode {
+                discovered_by: None,
+                community: None,
+                ..member.clone()
+            });
+            node.address = id;
+            node.depth = match (node.depth, member.depth) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            node.root = node.root.or(member.root);
+            node.flags = NodeFlags {
+                hub: node.flags.hub || member.flags.hub,
+                contract: node.flags.contract || member.flags.contract,
+                unexplored: node.flags.unexplored || member.flags.unexplored,
+                peel: node.flags.peel || member.flags.peel,
+            };
+        }
+
+        let mut collapsed = TransferGraph::new();
+        for (_, mut node) in merged {
+            let size = sizes[&node.address];
+            if size > 1 {
+                node.label = Some(format!("{} addresses", size));
+            }
+            collapsed.add_node(node);
+        }
+        for edge in graph.edge_references() {
+            let from = self.entity_of(&graph[edge.source()].address);
+            let to = self.entity_of(&graph[edge.target()].address);
+            let (from, to) = (
+                collapsed.node_index(&from).unwrap(),
+                collapsed.node_index(&to).unwrap(),
+            );
+            collapsed.add_edge(from, to, edge.weight().clone());
+        }
+        collapsed
+    }
+}
+
+// Union-find over node indices, remembering which heuristics joined each set
+struct Clusters {
+    parent: Vec<usize>,
+    heuristics: Vec<BTreeSet<ClusterHeuristic>>,
+}
+
+impl Clusters {
+    fn new(len: usize) -> Self {
+        Self {
+            parent: (0..len).collect(),
+            heuristics: vec![BTreeSet::new(); len],
+        }
+    }
+
+    fn find(&mut self, node: usize) -> usize {
+        let mut root = node;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut node = node;
+        while self.parent[node] != root {
+            let next = self.parent[node];
+            self.parent[node] = root;
+            node = next;
+        }
+        root
+    }
+
+    // The lower index stays the root, so entity ids don't depend on the order of unions
+    fn union(&mut self, a: usize, b: usize, heuristic: ClusterHeuristic) {
+        let (a, b) = (self.find(a), self.find(b));
+        let (root, child) = (a.min(b), a.max(b));
+        if root != child {
+            self.parent[child] = root;
+            let moved = std::mem::take(&mut self.heuristics[child]);
+            self.heuristics[root].extend(moved);
+        }
+        self.heuristics[root].insert(heuristic);
+    }
+}
+
+/// Group the addresses of `graph` into entities with the heuristics in `options`.
+///
+/// These are heuristics, so treat the result as leads: a shared funder or a co-spend can be
+/// a coincidence, and ERC-20 transfers only reveal co-spending when one transaction moves
+/// funds out of several addresses, e.g. through `transferFrom` by one operator.
+pub fn cluster_addresses(graph: &TransferGraph, options: &ClusterOptions) -> EntityMap {
+    let mut clusters = Clusters::new(graph.node_count());
+
+    if options.max_funded > 0 {
+        common_funders(graph, options, &mut clusters);
+    }
+    if options.min_deposit_senders > 0 {
+        deposit_forwarding(graph, options, &mut clusters);
+    }
+    if options.min_co_spends > 0 {
+        co_spending(graph, options, &mut clusters);
+    }
+
+    let mut members: BTreeMap<usize, Vec<NodeIndex>> = BTreeMap::new();
+    for idx in graph.node_indices() {
+        members
+            .entry(clusters.find(idx.index()))
+            .or_default()
+            .push(idx);
+    }
+
+    let mut assignment = HashMap::new();
+    let mut entities = Vec::new();
+    for (root, group) in members {
+        let id = graph[NodeIndex::new(root)].address;
+        for idx in &group {
+            assignment.insert(graph[*idx].address, id);
+        }
+        if group.len() > 1 {
+            let mut members: Vec<Address> = group.iter().map(|idx| graph[*idx].address).collect();
+            members.sort();
+            entities.push(Entity {
+                id,
+                members,
+                heuristics: clusters.heuristics[root].iter().copied().collect(),
+            });
+        }
+    }
+    entities.sort_by(|a, b| b.members.len().cmp(&a.members.len()).then(a.id.cmp(&b.id)));
+
+    EntityMap {
+        assignment,
+        entities,
+    }
+}
+
+// Addresses whose earliest incoming transfer came from the same small-time funder
+fn common_funders(graph: &TransferGraph, options: &ClusterOptions, clusters: &mut Clusters) {
+    let mut funded: HashMap<NodeIndex, Vec<NodeIndex>> = HashMap::new();
+    for idx in graph.node_indices() {
+        let first = graph
+            .edges_directed(idx, Direction::Incoming)
+            .filter(|edge| edge.source() != idx)
+            .min_by_key(|edge| edge.weight().position());
+        if let Some(edge) = first
+            && !graph[edge.source()].flags.hub
+        {
+            funded.entry(edge.source()).or_default().push(idx);
+        }
+    }
+
+    for group in funded.values() {
+        if group.len() < 2 || group.len() > options.max_funded {
+            continue;
+        }
+        for idx in &group[1..] {
+            clusters.union(
+                group[0].index(),
+                idx.index(),
+                ClusterHeuristic::CommonFunder,
+            );
+        }
+    }
+}
+
+// Deposit addresses with several senders that forward to one consolidation wallet, which
+// has several such deposit addresses
+fn deposit_forwarding(graph: &TransferGraph, options: &ClusterOptions, clusters: &mut Clusters) {
+    let mut deposits: HashMap<NodeIndex, Vec<NodeIndex>> = HashMap::new();
+    for idx in graph.node_indices() {
+        if graph[idx].flags.hub || graph[idx].flags.contract {
+            continue;
+        }
+        let mut recipients = graph
+            .neighbors_directed(idx, Direction::Outgoing)
+            .filter(|other| *other != idx);
+        let Some(wallet) = recipients.next() else {
+            continue;
+        };
+        if recipients.any(|other| other != wallet) || graph[wallet].flags.hub {
+            continue;
+        }
+        let senders: BTreeSet<NodeIndex> = graph
+            .neighbors_directed(idx, Direction::Incoming)
+            .filter(|other| *other != idx && *other != wallet)
+            .collect();
+        if senders.len() >= options.min_deposit_senders {
+            deposits.entry(wallet).or_default().push(idx);
+        }
+    }
+
+    for (wallet, group) in deposits {
+        if group.len() < options.min_deposit_senders {
+            continue;
+        }
+        for idx in group {
+            clusters.union(
+                wallet.index(),
+                idx.index(),
+                ClusterHeuristic::DepositForwarding,
+            );
+        }
+    }
+}
+
+// Pairs of addresses that both sent transfers in enough of the same transactions. Hubs and
+// contracts send in other people's transactions all the time, so they're left out.
+fn co_spending(graph: &TransferGraph, options: &ClusterOptions, clusters: &mut Clusters) {
+    let mut senders: HashMap<TxHash, BTreeSet<NodeIndex>> = HashMap::new();
+    for edge in graph.edge_references() {
+        let flags = &graph[edge.source()].flags;
+        if flags.hub || flags.contract {
+            continue;
+        }
+        senders
+            .entry(edge.weight().tx_hash)
+            .or_default()
+            .insert(edge.source());
+    }
+
+    let mut shared: BTreeMap<(NodeIndex, NodeIndex), usize> = BTreeMap::new();
+    for group in senders.values() {
+        if group.len() > options.max_co_spenders {
+            continue;
+        }
+        let group: Vec<NodeIndex> = group.iter().copied().collect();
+        for (i, a) in group.iter().enumerate() {
+            for b in &group[i + 1..] {
+                *shared.entry((*a, *b)).or_default() += 1;
+            }
+        }
+    }
+
+    for ((a, b), count) in shared {
+        if count >= options.min_co_spends {
+            clusters.union(a.index(), b.index(), ClusterHeuristic::CoSpending);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::types::Transfer;
+    use alloy_primitives::B256;
+
+    // 1 first-funds 2 and 3, but not 4, which 9 got to first. 3 and 5 both pay 6 in two
+    // transactions.
+    fn funded_and_co_spending() -> TransferGraph {
+        let in_tx = |n, from, to, log_index| Transfer {
+            tx_hash: B256::repeat_byte(n),
+            ..transfer(from, to, 10 + n as u64, log_index, 5)
+        };
+        graph_of(&[
+            transfer(9, 4, 1, 0, 5),
+            transfer(1, 2, 2, 0, 5),
+            transfer(1, 3, 3, 0, 5),
+            transfer(1, 4, 5, 0, 5),
+            in_tx(1, 3, 6, 0),
+            in_tx(1, 5, 6, 1),
+            in_tx(2, 3, 6, 0),
+            in_tx(2, 5, 6, 1),
+        ])
+    }
+
+    fn members(entities: &EntityMap) -> Vec<(Address, Vec<Address>, Vec<ClusterHeuristic>)> {
+        entities
+            .entities
+            .iter()
+            .map(|entity| (entity.id, entity.members.clone(), entity.heuristics.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn heuristics_join_into_one_entity() {
+        let graph = funded_and_co_spending();
+        let entities = cluster_addresses(&graph, &ClusterOptions::new());
+
+        // 2 was added before 3 and 5, so it names the entity
+        assert_eq!(
+            members(&entities),
+            vec![(
+                address(2),
+                vec![address(2), address(3), address(5)],
+                vec![ClusterHeuristic::CommonFunder, ClusterHeuristic::CoSpending]
+            )]
+        );
+        assert_eq!(entities.entity_of(&address(5)), address(2));
+        assert_eq!(entities.entity_of(&address(4)), address(4));
+
+        let collapsed = entities.collapse(&graph);
+        assert_eq!(collapsed.node_count(), 5);
+        assert_eq!(collapsed.edge_count(), graph.edge_count());
+        let entity = collapsed.node(&address(2)).unwrap();
+        assert_eq!(entity.label.as_deref(), Some("3 addresses"));
+        assert_eq!(collapsed.neighbors_out(&address(2)), vec![address(6)]);
+    }
+
+    #[test]
+    fn thresholds_turn_heuristics_off() {
+        let graph = funded_and_co_spending();
+
+        let funded_only = cluster_addresses(&graph, &ClusterOptions::new().with_min_co_spends(3));
+        assert_eq!(
+            members(&funded_only),
+            vec![(
+                address(2),
+                vec![address(2), address(3)],
+                vec![ClusterHeuristic::CommonFunder]
+            )]
+        );
+
+        let co_spending_only = cluster_addresses(&graph, &ClusterOptions::new().with_max_funded(1));
+        assert_eq!(
+            members(&co_spending_only),
+            vec![(
+                address(3),
+                vec![address(3), address(5)],
+                vec![ClusterHeuristic::CoSpending]
+            )]
+        );
+
+        let batches = ClusterOptions::new()
+            .with_max_funded(0)
+            .with_max_co_spenders(1);
+        assert!(cluster_addresses(&graph, &batches).entities.is_empty());
+    }
+
+    #[test]
+    fn deposit_addresses_join_their_consolidation_wallet() {
+        let mut graph = graph_of(&[
+            transfer(10, 20, 1, 0, 5),
+            transfer(11, 20, 1, 1, 5),
+            transfer(12, 21, 1, 2, 5),
+            transfer(13, 21, 1, 3, 5),
+            transfer(20, 30, 2, 0, 10),
+            transfer(21, 30, 2, 1, 10),
+        ]);
+        let entities = cluster_addresses(&graph, &ClusterOptions::new());
+
+        assert_eq!(
+            members(&entities),
+            vec![(
+                address(20),
+                vec![address(20), address(21), address(30)],
+                vec![ClusterHeuristic::DepositForwarding]
+            )]
+        );
+        let stricter = ClusterOptions::new().with_min_deposit_senders(3);
+        assert!(cluster_addresses(&graph, &stricter).entities.is_empty());
+
+        // Nothing forwards into a hub as a deposit address
+        let wallet = graph.node_index(&address(30)).unwrap();
+        graph[wallet].flags.hub = true;
+        assert!(
+            cluster_addresses(&graph, &ClusterOptions::new())
+                .entities
+                .is_empty()
+        );
+    }
+}
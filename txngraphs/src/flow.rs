@@ -0,0 +1,220 @@
+use crate::summary::decimal;
+use crate::types::TransferGraph;
+use alloy_primitives::{
+    Address,
+    aliases::{I256, U256},
+};
+use petgraph::visit::EdgeRef;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+/// FlowRole
+///
+/// What an address did with one token inside the graph.
+/// - `Source`: only sent, e.g. the root of a trail.
+/// - `Sink`: only received, so the funds stopped there as far as the graph knows.
+/// - `Intermediary`: both received and sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum FlowRole {
+    Source,
+    Sink,
+    Intermediary,
+}
+
+impl Display for FlowRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Source => write!(f, "source"),
+            Self::Sink => write!(f, "sink"),
+            Self::Intermediary => write!(f, "intermediary"),
+        }
+    }
+}
+
+/// NetFlow
+///
+/// Raw amounts of one token an address received and sent within the graph.
+///
+/// `unexplored` addresses were never expanded, so a sink among them may only be where the
+/// traversal stopped rather than where the funds did.
+#[derive(Debug, Clone, Serialize)]
+pub struct NetFlow {
+    pub address: Address,
+    pub token: Address,
+    #[serde(serialize_with = "decimal")]
+    pub inflow: U256,
+    #[serde(serialize_with = "decimal")]
+    pub outflow: U256,
+    pub role: FlowRole,
+    pub unexplored: bool,
+}
+
+impl NetFlow {
+    /// Inflow minus outflow, saturating at the `I256` bounds.
+    pub fn net(&self) -> I256 {
+        let inflow = I256::try_from(self.inflow).unwrap_or(I256::MAX);
+        let outflow = I256::try_from(self.outflow).unwrap_or(I256::MAX);
+        inflow.saturating_sub(outflow)
+    }
+
+    /// How much more the address received than it sent, or zero.
+    pub fn retained(&self) -> U256 {
+        self.inflow.saturating_sub(self.outflow)
+    }
+
+    /// How much more the address sent than it received, or zero.
+    pub fn released(&self) -> U256 {
+        self.outflow.saturating_sub(self.inflow)
+    }
+}
+
+/// Net flow of every (address, token) pair in `graph`, sorted by token and then address.
+///
+/// Self-transfers count as both inflow and outflow, so they never change the net.
+pub fn compute_net_flows(graph: &TransferGraph) -> Vec<NetFlow> {
+    let mut totals: BTreeMap<(Address, Address), (U256, U256)> = BTreeMap::new();
+    for edge in graph.edge_references() {
+        let transfer = edge.weight();
+        let sent = totals
+            .entry((transfer.token, graph[edge.source()].address))
+            .or_default();
+        sent.1 = sent.1.saturating_add(transfer.amount);
+        let received = totals
+            .entry((transfer.token, graph[edge.target()].address))
+            .or_default();
+        received.0 = received.0.saturating_add(transfer.amount);
+    }
+
+    totals
+        .into_iter()
+        .map(|((token, address), (inflow, outflow))| {
+            let role = if inflow.is_zero() {
+                FlowRole::Source
+            } else if outflow.is_zero() {
+                FlowRole::Sink
+            } else {
+                FlowRole::Intermediary
+            };
+            let unexplored = graph
+                .node(&address)
+                .is_some_and(|node| node.flags.unexplored);
+            NetFlow {
+                address,
+                token,
+                inflow,
+                outflow,
+                role,
+                unexplored,
+            }
+        })
+        .collect()
+}
+
+/// TokenFlowReport
+///
+/// Where one token came from and where it ended up. `sources` are the addresses that sent
+/// more than they received, which includes intermediaries whose inflow is outside the graph.
+/// `destinations` are the addresses that kept some of it, most retained first, and
+/// `total_retained` is what they kept together, which is also what the sources released.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenFlowReport {
+    pub token: Address,
+    pub sources: Vec<NetFlow>,
+    pub destinations: Vec<NetFlow>,
+    #[serde(serialize_with = "decimal")]
+    pub total_retained: U256,
+}
+
+impl TokenFlowReport {
+    /// Share of `total_retained` kept by `flow`, in percent.
+    pub fn percent_retained(&self, flow: &NetFlow) -> f64 {
+        if self.total_retained.is_zero() {
+            return 0.0;
+        }
+        f64::from(flow.retained()) * 100.0 / f64::from(self.total_retained)
+    }
+}
+
+impl Display for TokenFlowReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Token {:.36}, {} moved:",
+            self.token, self.total_retained
+        )?;
+        for source in &self.sources {
+            writeln!(
+                f,
+                "  from {:.36} released {} as {}",
+                source.address,
+                source.released(),
+                source.role
+            )?;
+        }
+        for destination in &self.destinations {
+            write!(
+                f,
+                "  to {:.36} kept {} ({:.2}%) as {}",
+                destination.address,
+                destination.retained(),
+                self.percent_retained(destination),
+                destination.role
+            )?;
+            if destination.unexplored {
+                write!(f, ", unexplored")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The "where did the money end up" report, one entry per token, most retained first.
+///
+/// `top` keeps only that many destinations per token, while `total_retained` still covers
+/// them all.
+pub fn flow_report(graph: &TransferGraph, top: Option<usize>) -> Vec<TokenFlowReport> {
+    let mut by_token: BTreeMap<Address, Vec<NetFlow>> = BTreeMap::new();
+    for flow in compute_net_flows(graph) {
+        by_token.entry(flow.token).or_default().push(flow);
+    }
+
+    let mut reports: Vec<TokenFlowReport> = by_token
+        .into_iter()
+        .map(|(token, flows)| {
+            let (mut sources, mut destinations): (Vec<NetFlow>, Vec<NetFlow>) = flows
+                .into_iter()
+                .filter(|flow| flow.inflow != flow.outflow)
+                .partition(|flow| flow.outflow > flow.inflow);
+            sources.sort_by(|a, b| {
+                b.released()
+                    .cmp(&a.released())
+                    .then(a.address.cmp(&b.address))
+            });
+            destinations.sort_by(|a, b| {
+                b.retained()
+                    .cmp(&a.retained())
+                    .then(a.address.cmp(&b.address))
+            });
+            let total_retained = destinations.iter().fold(U256::ZERO, |total, flow| {
+                total.saturating_add(flow.retained())
+            });
+            if let Some(top) = top {
+                destinations.truncate(top);
+            }
+            TokenFlowReport {
+                token,
+                sources,
+                destinations,
+                total_retained,
+            }
+        })
+        .collect();
+    reports.sort_by(|a, b| {
+        b.total_retained
+            .cmp(&a.total_retained)
+            .then(a.token.cmp(&b.token))
+    });
+    reports
+}
@@ -1,4 +1,4 @@
-use crate::types::TransferGraph;
+use crate::types::{TransferGraph, TransferNode};
 use anyhow::{Context, Result};
 use copypasta::{ClipboardContext, ClipboardProvider};
 use graphviz_rust::{
@@ -6,10 +6,6 @@ use graphviz_rust::{
     exec, parse,
     printer::PrinterContext,
 };
-use petgraph::algo::tarjan_scc;
-use petgraph::visit::EdgeRef;
-use std::clone::Clone;
-use std::collections::HashMap;
 use std::{fmt::Write, fs};
 
 // Oops I/Claude didn't realize petgraph had DOT exports already
@@ -26,8 +22,8 @@ pub fn write_graph_to_dot(graph: &TransferGraph) -> String {
 
     // Add nodes (addresses)
     for node_idx in graph.node_indices() {
-        let address = graph[node_idx];
-        writeln!(dot, "  \"{}\" [label=\"{:.36}...\"];", address, address).unwrap();
+        let node = &graph[node_idx];
+        writeln!(dot, "  \"{}\" [{}];", node.address, node_attributes(node)).unwrap();
     }
 
     writeln!(dot).unwrap();
@@ -35,8 +31,8 @@ pub fn write_graph_to_dot(graph: &TransferGraph) -> String {
     // Add edges (transfers)
     for edge_idx in graph.edge_indices() {
         let (from_idx, to_idx) = graph.edge_endpoints(edge_idx).unwrap();
-        let from_addr = graph[from_idx];
-        let to_addr = graph[to_idx];
+        let from_addr = graph[from_idx].address;
+        let to_addr = graph[to_idx].address;
         let transfer = &graph[edge_idx];
 
         let amount_str = transfer.amount.to_string();
@@ -53,6 +49,27 @@ pub fn write_graph_to_dot(graph: &TransferGraph) -> String {
     dot
 }
 
+// Label, plus a box for contracts, a fill color per community and a red outline for peel
+// chain wallets
+fn node_attributes(node: &TransferNode) -> String {
+    let mut attributes = format!("label=\"{:.36}...\"", node.address);
+    if node.flags.contract {
+        write!(attributes, " shape=box").unwrap();
+    }
+    if let Some(community) = node.community {
+        write!(
+            attributes,
+            " style=filled fillcolor=\"/set312/{}\"",
+            community % 12 + 1
+        )
+        .unwrap();
+    }
+    if node.flags.peel {
+        write!(attributes, " color=red penwidth=2").unwrap();
+    }
+    attributes
+}
+
 /// Call write_graph_to_dot() and copy result to clipboard
 ///
 /// Probably only use this for pretty small graphs.
@@ -81,8 +98,8 @@ pub fn save_graph_as_svg(graph: &TransferGraph, filename: &str) -> Result<String
 
     // Add nodes (addresses)
     for node_idx in graph.node_indices() {
-        let address = graph[node_idx];
-        writeln!(dot, "  \"{}\" [label=\"{:.36}...\"];", address, address).unwrap();
+        let node = &graph[node_idx];
+        writeln!(dot, "  \"{}\" [{}];", node.address, node_attributes(node)).unwrap();
     }
 
     writeln!(dot).unwrap();
@@ -90,8 +107,8 @@ pub fn save_graph_as_svg(graph: &TransferGraph, filename: &str) -> Result<String
     // Add edges (transfers)
     for edge_idx in graph.edge_indices() {
         let (from_idx, to_idx) = graph.edge_endpoints(edge_idx).unwrap();
-        let from_addr = graph[from_idx];
-        let to_addr = graph[to_idx];
+        let from_addr = graph[from_idx].address;
+        let to_addr = graph[to_idx].address;
         let transfer = &graph[edge_idx];
 
         let amount_str = transfer.amount.to_string();
@@ -123,34 +140,9 @@ pub fn save_graph_as_svg(graph: &TransferGraph, filename: &str) -> Result<String
     Ok(output_path)
 }
 
-// TODO: move this to impl TransferGraph
+/// Every strongly connected component with more than one address, as its own graph.
+///
+/// See `TransferGraph::closed_loops`, and `cycles::find_cycles` for the individual loops.
 pub fn find_closed_loops(graph: &TransferGraph) -> Vec<TransferGraph> {
-    let mut closed_loops = Vec::new();
-
-    let mut tarjan_graph = tarjan_scc(&graph);
-    tarjan_graph.retain(|scc| scc.len() > 1);
-
-    for scc in tarjan_graph {
-        let mut index_mapping = HashMap::new();
-        let mut loop_graph = TransferGraph::new();
-
-        for node in &scc {
-            let address = graph[*node];
-            let new_idx = loop_graph.add_node(address);
-            index_mapping.insert(*node, new_idx);
-        }
-
-        for edge in graph.edge_references() {
-            let (source, target) = (edge.source(), edge.target());
-            if scc.contains(&source) && scc.contains(&target) {
-                let new_source_idx = index_mapping[&source];
-                let new_target_idx = index_mapping[&target];
-                loop_graph.add_edge(new_source_idx, new_target_idx, edge.weight().clone());
-            }
-        }
-
-        closed_loops.push(loop_graph);
-    }
-
-    closed_loops
+    graph.closed_loops()
 }
@@ -7,9 +7,51 @@ pub mod data_sources;
 pub mod reth_source;
 // Module for building the transfer graph from a TransferDataSource
 pub mod traversal;
+// Pluggable rules for which transfers and addresses the traversal follows
+pub mod policy;
+// Progress callbacks and cancellation for long traversals
+pub mod progress;
+// Saving and resuming traversal state
+pub mod checkpoint;
+// Growing a graph incrementally
+pub mod session;
 
 // Types and functions for summarizing a transfer graph
 pub mod summary;
 
 // Module with utility functions for visualizing and measuring a transfer graph
 pub mod graph_utils;
+
+// Per-address degree statistics and the "strandedness" distribution
+pub mod degree;
+
+// Net flow per address and token, and where the funds ended up
+pub mod flow;
+
+// Elementary cycles with their volume and timing
+pub mod cycles;
+
+// Round-trip and wash trading detection
+pub mod wash;
+
+// PageRank, betweenness and closeness centrality
+pub mod centrality;
+
+// Louvain communities and k-core decomposition
+pub mod community;
+
+// Heuristic clustering of addresses into entities
+pub mod entity;
+
+// Peel chain detection
+pub mod peel;
+
+// Taint propagation over a transfer graph (poison, haircut and FIFO models)
+pub mod taint;
+
+// Watchlists and the sinks their alerts are delivered to
+pub mod watch;
+
+// In-memory data source and transfer helpers for the unit tests
+#[cfg(test)]
+mod test_utils;
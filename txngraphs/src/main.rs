@@ -1,10 +1,16 @@
-use alloy_primitives::Address;
-use anyhow::Result;
+use alloy_primitives::{Address, B256, U256};
+use anyhow::{Result, bail};
 use clap::Parser;
-use std::str::FromStr;
+use std::{
+    collections::HashSet, io::Write, path::PathBuf, str::FromStr, sync::Arc, time::Duration,
+};
 use tracing::{info, warn};
 use tracing_subscriber;
-use txngraphs::{data_sources::*, graph_utils::*, reth_source::*, summary::*, traversal::*};
+use txngraphs::{
+    centrality::*, community::*, cycles::*, data_sources::*, degree::*, entity::*, flow::*,
+    graph_utils::*, peel::*, policy::*, progress::*, reth_source::*, summary::*, taint::*,
+    traversal::*, wash::*, watch::*,
+};
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -20,12 +26,209 @@ struct Args {
         value_delimiter = ','
     )]
     token_address: Vec<String>,
+    /// Follow every ERC-20 token instead of --token-address
+    #[arg(long)]
+    any_token: bool,
+    /// Build the graph of every transfer of the tokens in range instead of tracing from an
+    /// address. Nothing is expanded, so flags that only affect expansion are rejected
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "best_first",
+            "tx_hash",
+            "any_token",
+            "temporal",
+            "max_hop_blocks",
+            "skip_contracts",
+            "hub_threshold",
+            "hub_multiplier",
+            "parallel",
+            "progress",
+        ]
+    )]
+    token_graph: bool,
+    /// Start from every transfer in this transaction instead of the root address, tracing
+    /// its recipients from the transaction's block to --block-end
+    #[arg(long, conflicts_with = "best_first")]
+    tx_hash: Option<String>,
     #[arg(short = 'd', long, default_value = "1")]
     max_depth: usize,
     #[arg(short = 's', long, default_value = "8610738")]
     block_start: u64,
     #[arg(short = 'e', long, default_value = "8630738")]
     block_end: u64,
+    /// Only follow transfers made after funds reached each address
+    #[arg(long)]
+    temporal: bool,
+    /// In temporal mode, stop following an address this many blocks after it received funds
+    #[arg(long)]
+    max_hop_blocks: Option<u64>,
+    /// Ignore transfers below this raw amount
+    #[arg(long)]
+    min_amount: Option<String>,
+    /// Ignore transfers to or from these addresses
+    #[arg(long, value_delimiter = ',')]
+    exclude: Vec<String>,
+    /// Ignore mints and burns
+    #[arg(long)]
+    exclude_zero_address: bool,
+    /// Ignore transfers from an address to itself
+    #[arg(long)]
+    exclude_self_transfers: bool,
+    /// Don't expand addresses that have contract code
+    #[arg(long)]
+    skip_contracts: bool,
+    /// Look up which addresses have contract code after the build, so --explain marks them
+    /// and --entities leaves them out of co-spending. Costs one state read per node
+    #[arg(long)]
+    flag_contracts: bool,
+    /// Don't expand addresses with more than this many transfers in range
+    #[arg(long)]
+    hub_threshold: Option<usize>,
+    /// Learn the hub cutoff as this multiple of the median transfer count seen so far
+    #[arg(long, conflicts_with = "hub_threshold")]
+    hub_multiplier: Option<f64>,
+    /// Fetch each BFS tier's addresses concurrently
+    #[arg(long)]
+    parallel: bool,
+    /// Log progress as each address and depth is processed
+    #[arg(long)]
+    progress: bool,
+    /// Stop after this many seconds and keep the partial graph
+    #[arg(long)]
+    timeout_secs: Option<u64>,
+    /// Save BFS state to this file after every depth. Best-first and token graph builds
+    /// don't checkpoint
+    #[arg(long, conflicts_with_all = ["best_first", "token_graph"])]
+    checkpoint: Option<PathBuf>,
+    /// Continue from the --checkpoint file if it exists
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+    /// Expand the frontier node with the largest inflow first instead of going tier by tier
+    #[arg(long)]
+    best_first: bool,
+    /// Best-first: stop once the graph has this many nodes
+    #[arg(long, requires = "best_first")]
+    max_nodes: Option<usize>,
+    /// Best-first: stop once the graph has this many edges
+    #[arg(long, requires = "best_first")]
+    max_edges: Option<usize>,
+    /// Best-first: stop after this many data source queries
+    #[arg(long, requires = "best_first")]
+    max_queries: Option<usize>,
+    /// Propagate taint from the root address with this model (poison, haircut or fifo)
+    #[arg(long)]
+    taint_model: Option<TaintModel>,
+    /// Raw amount of the token the root starts out holding as tainted; all outflows if unset.
+    /// Needs a single --token-address, since raw amounts don't compare across tokens
+    #[arg(long, requires = "taint_model", conflicts_with = "any_token")]
+    taint_amount: Option<String>,
+    /// Addresses to raise alerts for when they show up in the graph
+    #[arg(long, value_delimiter = ',')]
+    watch: Vec<String>,
+    /// Minimum raw transfer amount for a watchlist alert
+    #[arg(long, default_value = "0")]
+    watch_min_amount: String,
+    /// Also alert when funds land within this many hops upstream of a watched address
+    #[arg(long)]
+    watch_hops: Option<usize>,
+    /// Print alerts to stdout as JSON lines, mixed in with the rest of the output
+    #[arg(long)]
+    alert_stdout: bool,
+    /// Append alerts as JSON lines to this file
+    #[arg(long)]
+    alert_file: Option<String>,
+    /// POST alerts as JSON to this http:// URL
+    #[arg(long)]
+    alert_webhook: Option<String>,
+    /// Print the chain of transfers that led the traversal to these addresses
+    #[arg(long, value_delimiter = ',')]
+    explain: Vec<String>,
+    /// Export the summary table as csv, json or markdown instead of printing it
+    #[arg(long)]
+    summary_format: Option<SummaryFormat>,
+    /// Write the exported summary to this file instead of stdout
+    #[arg(long, requires = "summary_format")]
+    summary_out: Option<PathBuf>,
+    /// Summary export: only pairs with at least this many transfers
+    #[arg(long, requires = "summary_format", default_value = "0")]
+    summary_min_transfers: usize,
+    /// Summary export: only pairs that moved at least this raw amount
+    #[arg(long, requires = "summary_format")]
+    summary_min_amount: Option<String>,
+    /// Summary export: only pairs that moved this token
+    #[arg(long, requires = "summary_format")]
+    summary_token: Option<String>,
+    /// Summary export: keep the first this many rows after sorting
+    #[arg(long, requires = "summary_format")]
+    summary_top: Option<usize>,
+    /// Summary export: sort by address, transfers, amount, first-block or last-block
+    #[arg(long, requires = "summary_format", default_value = "address")]
+    summary_sort: SummarySort,
+    /// Print the "% of nodes with >= N transfers" degree distribution
+    #[arg(long)]
+    degree_stats: bool,
+    /// Write per-address degree stats to this file, in the --summary-format
+    #[arg(long, requires = "summary_format")]
+    degree_out: Option<PathBuf>,
+    /// Print where each token ended up: sources, and the addresses that kept funds
+    #[arg(long)]
+    flow_report: bool,
+    /// Flow report: list at most this many destinations per token
+    #[arg(long, requires = "flow_report", default_value = "10")]
+    flow_top: usize,
+    /// Print the elementary cycles in the graph, time-ordered ones first
+    #[arg(long)]
+    cycles: bool,
+    /// Cycles: longest cycle to look for, in hops
+    #[arg(long, requires = "cycles", default_value = "4")]
+    cycle_max_length: usize,
+    /// Cycles: stop after finding this many
+    #[arg(long, requires = "cycles")]
+    cycle_limit: Option<usize>,
+    /// Report transfers whose funds came back to the sender, and how much volume was circular
+    #[arg(long)]
+    wash: bool,
+    /// Wash: only count funds that came back within this many blocks
+    #[arg(long, requires = "wash")]
+    wash_window: Option<u64>,
+    /// Wash: most transfers on the way back, counting the outgoing one
+    #[arg(long, requires = "wash", default_value = "4")]
+    wash_hops: usize,
+    /// Print the addresses ranked by PageRank, with personalized PageRank from the root,
+    /// betweenness and closeness
+    #[arg(long)]
+    centrality: bool,
+    /// Centrality: weight links by none, count or amount
+    #[arg(long, requires = "centrality", default_value = "count")]
+    centrality_weight: CentralityWeight,
+    /// Centrality: list at most this many addresses
+    #[arg(long, requires = "centrality", default_value = "20")]
+    centrality_top: usize,
+    /// Print the communities found in the summary graph, with their internal and external volume
+    #[arg(long)]
+    communities: bool,
+    /// Print the addresses with at least this many counterparties among each other
+    #[arg(long)]
+    k_core: Option<usize>,
+    /// Group addresses into entities by common funder, deposit forwarding and co-spending,
+    /// and print them. --wash then counts funds returning to the sender's entity
+    #[arg(long)]
+    entities: bool,
+    /// Print peel chains: wallets forwarding most of their funds to a fresh wallet while
+    /// peeling small amounts off
+    #[arg(long)]
+    peel_chains: bool,
+    /// Peel chains: fewest hops for a chain to be reported
+    #[arg(long, requires = "peel_chains", default_value = "3")]
+    peel_min_length: usize,
+    /// Write the graph as DOT to this file, with contracts, communities and peel chain
+    /// wallets marked
+    #[arg(long)]
+    dot_out: Option<PathBuf>,
+    /// Render the graph as SVG to results/<file>. Needs graphviz installed
+    #[arg(long)]
+    svg_out: Option<String>,
 }
 
 fn main() -> Result<()> {
@@ -41,39 +244,411 @@ fn main() -> Result<()> {
     if block_end < block_start {
         warn!("Block end is less than block start. Please check your input.")
     }
-    let token_addresses: Vec<Address> = args
-        .token_address
-        .iter()
-        .map(|addr| Address::from_str(addr))
-        .collect::<Result<Vec<Address>, _>>()?;
+    // An empty token list means any token
+    let token_addresses: Vec<Address> = if args.any_token {
+        Vec::new()
+    } else {
+        args.token_address
+            .iter()
+            .map(|addr| Address::from_str(addr))
+            .collect::<Result<Vec<Address>, _>>()?
+    };
     info!("Token addresses: {:?}", token_addresses);
+    // Raw amounts only mean something for one token
+    if args.taint_amount.is_some() && token_addresses.len() != 1 {
+        bail!("--taint-amount needs exactly one --token-address");
+    }
     info!("Have {} blocks to process.", block_end - block_start);
 
     let db_path = String::from("/Users/zach.wong/Documents/unichain/unichain");
 
+    let mut options = TraversalOptions::new().with_parallel(args.parallel);
+
     info!("Initializing RethTransferDataSource");
-    let reth_source = RethTransferDataSource::new(db_path);
+    let mut reth_source = RethTransferDataSource::new(db_path);
+    if args.progress {
+        let observer: Arc<dyn TraversalObserver> = Arc::new(LogObserver);
+        reth_source = reth_source.with_observer(observer.clone());
+        options = options.with_observer(observer);
+    }
+    if let Some(path) = &args.checkpoint {
+        options = options.with_checkpoint(path, args.resume);
+    }
+    if let Some(secs) = args.timeout_secs {
+        let cancellation = CancellationToken::new().with_timeout(Duration::from_secs(secs));
+        reth_source = reth_source.with_cancellation(cancellation.clone());
+        options = options.with_cancellation(cancellation);
+    }
+
+    if args.temporal {
+        let mut window = TemporalWindow::new();
+        if let Some(blocks) = args.max_hop_blocks {
+            window = window.with_max_hop_blocks(blocks);
+        }
+        options = options.with_temporal(window);
+    }
+
+    let mut policies: Vec<Box<dyn ExpansionPolicy>> = Vec::new();
+    if let Some(min_amount) = &args.min_amount {
+        policies.push(Box::new(MinAmount::new(U256::from_str(min_amount)?)));
+    }
+    if !args.exclude.is_empty() {
+        let excluded = args
+            .exclude
+            .iter()
+            .map(|addr| Address::from_str(addr))
+            .collect::<Result<HashSet<Address>, _>>()?;
+        policies.push(Box::new(ExcludeAddresses(excluded)));
+    }
+    if args.exclude_zero_address {
+        policies.push(Box::new(NoZeroAddress));
+    }
+    if args.exclude_self_transfers {
+        policies.push(Box::new(NoSelfTransfers));
+    }
+    if args.skip_contracts {
+        policies.push(Box::new(NoContractExpansion));
+    }
+    if !policies.is_empty() {
+        options = options.with_policy(AllOf(policies));
+    }
+    if let Some(threshold) = args.hub_threshold {
+        options = options.with_hub_cutoff(HubCutoff::TransferCount(threshold));
+    }
+    if let Some(multiplier) = args.hub_multiplier {
+        options = options.with_hub_cutoff(HubCutoff::Learned {
+            multiplier,
+            min_samples: 20,
+            floor: 100,
+        });
+    }
+
     info!("Building transfer graph");
-    let graph = build_transfer_graph(
-        &reth_source,
-        root_address,
-        block_start,
-        block_end,
-        &token_addresses,
-        max_depth,
-    )?;
-
-    info!("Graph built successfully");
+    let TraversalResult {
+        status,
+        mut graph,
+        truncated,
+        frontier,
+    } = if args.best_first {
+        let mut budget = TraversalBudget::new();
+        if let Some(max_nodes) = args.max_nodes {
+            budget = budget.with_max_nodes(max_nodes);
+        }
+        if let Some(max_edges) = args.max_edges {
+            budget = budget.with_max_edges(max_edges);
+        }
+        if let Some(max_queries) = args.max_queries {
+            budget = budget.with_max_queries(max_queries);
+        }
+        build_transfer_graph_best_first(
+            &reth_source,
+            root_address,
+            block_start,
+            block_end,
+            &token_addresses,
+            max_depth,
+            &options,
+            budget,
+            &InflowAmount,
+        )?
+    } else if args.token_graph {
+        build_token_transfer_graph(
+            &reth_source,
+            &token_addresses,
+            block_start,
+            block_end,
+            &options,
+        )?
+    } else if let Some(tx_hash) = &args.tx_hash {
+        build_transfer_graph_from_tx(
+            &reth_source,
+            B256::from_str(tx_hash)?,
+            block_end,
+            &token_addresses,
+            max_depth,
+            &options,
+        )?
+    } else {
+        build_transfer_graph_with_options(
+            &reth_source,
+            root_address,
+            block_start,
+            block_end,
+            &token_addresses,
+            max_depth,
+            &options,
+        )?
+    };
+
+    if status == TraversalStatus::Complete {
+        info!("Graph built successfully");
+    } else {
+        warn!(
+            "Traversal stopped early ({}), using the partial graph",
+            status
+        );
+    }
     info!(
         "Graph has {} nodes and {} edges",
         graph.node_count(),
         graph.edge_count()
     );
+    if args.flag_contracts {
+        flag_contracts(&mut graph, &reth_source)?;
+    }
+    if !frontier.is_empty() {
+        warn!(
+            "Budget ran out with {} addresses left unexplored",
+            frontier.len()
+        );
+        for node in frontier.iter().take(10) {
+            info!(
+                "Unexplored: {} at depth {} with inflow {}",
+                node.address, node.depth, node.inflow
+            );
+        }
+    }
+    for node in &truncated {
+        warn!(
+            "Did not expand hub {} at depth {}: {}",
+            node.address, node.depth, node.reason
+        );
+    }
 
     let summary: TransferSummary =
         TransferSummary::from_transfer_graph(&graph).with_summary_table();
 
-    print!("{}", summary);
+    match args.summary_format {
+        Some(format) => {
+            let mut query = SummaryQuery::new()
+                .with_min_transfers(args.summary_min_transfers)
+                .with_sort(args.summary_sort);
+            if let Some(amount) = &args.summary_min_amount {
+                query = query.with_min_amount(U256::from_str(amount)?);
+            }
+            if let Some(token) = &args.summary_token {
+                query = query.with_token(Address::from_str(token)?);
+            }
+            if let Some(top) = args.summary_top {
+                query = query.with_top(top);
+            }
+            match &args.summary_out {
+                Some(path) => {
+                    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+                    summary.export(&query, format, &mut file)?;
+                    file.flush()?;
+                    info!("Wrote summary to {}", path.display());
+                }
+                None => summary.export(&query, format, &mut std::io::stdout().lock())?,
+            }
+        }
+        None => print!("{}", summary),
+    }
+
+    if args.degree_stats || args.degree_out.is_some() {
+        let stats = compute_degree_stats(&graph);
+        if args.degree_stats {
+            println!("Degree distribution:");
+            for line in degree_distribution(&stats) {
+                print!("{}", line);
+            }
+        }
+        if let (Some(path), Some(format)) = (&args.degree_out, args.summary_format) {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            write_degree_stats(&stats, format, &mut file)?;
+            file.flush()?;
+            info!("Wrote degree stats to {}", path.display());
+        }
+    }
+
+    if args.flow_report {
+        for report in flow_report(&graph, Some(args.flow_top)) {
+            print!("{}", report);
+        }
+    }
+
+    if args.cycles {
+        let mut options = CycleOptions::new().with_max_length(args.cycle_max_length);
+        if let Some(limit) = args.cycle_limit {
+            options = options.with_max_cycles(limit);
+        }
+        let cycles = find_cycles(&graph, &options);
+        let ordered = cycles
+            .iter()
+            .filter(|cycle| cycle.is_time_ordered())
+            .count();
+        println!("Found {} cycles, {} time-ordered:", cycles.len(), ordered);
+        for cycle in &cycles {
+            print!("{}", cycle);
+        }
+    }
+
+    let entities = args
+        .entities
+        .then(|| cluster_addresses(&graph, &ClusterOptions::new()));
+    if let Some(entities) = &entities {
+        println!("Found {} entities:", entities.entities.len());
+        for entity in &entities.entities {
+            print!("{}", entity);
+        }
+    }
+
+    if args.wash {
+        let mut options = WashOptions::new().with_max_hops(args.wash_hops);
+        if let Some(window) = args.wash_window {
+            options = options.with_window(window);
+        }
+        if let Some(entities) = &entities {
+            options = options.with_clusters(entities.assignment.clone());
+        }
+        print!("{}", detect_wash_trading(&graph, &options));
+    }
+
+    if args.peel_chains {
+        let chains = find_peel_chains(
+            &graph,
+            &PeelOptions::new().with_min_length(args.peel_min_length),
+        );
+        println!("Found {} peel chains:", chains.len());
+        for chain in &chains {
+            print!("{}", chain);
+        }
+        mark_peel_chains(&mut graph, &chains);
+    }
+
+    if args.centrality {
+        let options = CentralityOptions::new()
+            .with_weight(args.centrality_weight)
+            .with_roots(vec![root_address]);
+        println!(
+            "{:<4} {:<42} {:>10} {:>12} {:>12} {:>10}",
+            "rank", "address", "pagerank", "personalized", "betweenness", "closeness"
+        );
+        for (rank, scores) in compute_centrality(&graph, &options)
+            .iter()
+            .take(args.centrality_top)
+            .enumerate()
+        {
+            println!(
+                "{:<4} {:<42} {:>10.6} {:>12.6} {:>12.6} {:>10.6}",
+                rank + 1,
+                scores.address,
+                scores.pagerank,
+                scores.personalized_pagerank.unwrap_or_default(),
+                scores.betweenness,
+                scores.closeness
+            );
+        }
+    }
+
+    if args.communities {
+        let communities = detect_communities(&summary, &CommunityOptions::new());
+        println!(
+            "Found {} communities, modularity {:.4}:",
+            communities.communities.len(),
+            communities.modularity
+        );
+        for community in &communities.communities {
+            print!("{}", community);
+        }
+        communities.apply(&mut graph);
+    }
+
+    if let Some(k) = args.k_core {
+        let members = k_core(&summary, k);
+        println!("{}-core: {} addresses", k, members.len());
+        for address in members {
+            println!("  {}", address);
+        }
+    }
+
+    for addr in &args.explain {
+        let address = Address::from_str(addr)?;
+        let Some(path) = graph.explain_path(&address) else {
+            warn!("{} is not in the graph", address);
+            continue;
+        };
+        println!("Path to {}:", address);
+        let contract = |is_contract: bool| if is_contract { " (contract)" } else { "" };
+        for edge_idx in path {
+            let (from_idx, to_idx) = graph.edge_endpoints(edge_idx).unwrap();
+            let transfer = &graph[edge_idx];
+            println!(
+                "  {:.36}{} -> {:.36}{} {} of {:.36} in block {} (tx {})",
+                graph[from_idx].address,
+                contract(graph[from_idx].flags.contract),
+                graph[to_idx].address,
+                contract(graph[to_idx].flags.contract),
+                transfer.amount,
+                transfer.token,
+                transfer.block_number,
+                transfer.tx_hash
+            );
+        }
+    }
+
+    if let Some(model) = args.taint_model {
+        let seeds: Vec<TaintSeed> = match &args.taint_amount {
+            Some(amount) => {
+                vec![
+                    TaintSeed::new(root_address, token_addresses[0])
+                        .with_amount(U256::from_str(amount)?),
+                ]
+            }
+            // Seed every token in the graph, which also covers any-token mode
+            None => summary
+                .tokens
+                .iter()
+                .map(|volume| TaintSeed::new(root_address, volume.token))
+                .collect(),
+        };
+        let taint = propagate_taint(&graph, &seeds, model);
+        print!("{}", taint);
+    }
+
+    if !args.watch.is_empty() {
+        let min_amount = U256::from_str(&args.watch_min_amount)?;
+        let mut watchlist = Watchlist::new();
+        for addr in &args.watch {
+            let mut rule = WatchRule::new(Address::from_str(addr)?)
+                .with_tokens(token_addresses.clone())
+                .with_min_amount(min_amount);
+            if let Some(hops) = args.watch_hops {
+                rule = rule.with_within_hops(hops);
+            }
+            watchlist = watchlist.with_rule(rule);
+        }
+
+        let mut watcher = Watcher::new(watchlist);
+        if args.alert_stdout {
+            watcher = watcher.with_sink(Box::new(StdoutSink));
+        }
+        if let Some(path) = &args.alert_file {
+            watcher = watcher.with_sink(Box::new(FileSink::new(path)?));
+        }
+        if let Some(url) = &args.alert_webhook {
+            watcher = watcher.with_sink(Box::new(WebhookSink::new(url)?));
+        }
+        if !args.alert_stdout && args.alert_file.is_none() && args.alert_webhook.is_none() {
+            warn!("No alert sink set; pass --alert-stdout, --alert-file or --alert-webhook");
+        }
+        let alerts = watcher.watchlist.check_graph(&graph);
+        let failures = watcher.dispatch(&alerts);
+        info!(
+            "Raised {} watchlist alerts, {} deliveries failed",
+            alerts.len(),
+            failures.len()
+        );
+    }
+
+    if let Some(path) = &args.dot_out {
+        std::fs::write(path, write_graph_to_dot(&graph))?;
+        info!("Wrote DOT graph to {}", path.display());
+    }
+    if let Some(filename) = &args.svg_out {
+        let path = save_graph_as_svg(&graph, filename)?;
+        info!("Wrote SVG graph to {}", path);
+    }
 
     // let closed_loops = find_closed_loops(&graph);
     // for x in closed_loops {
@@ -85,3 +660,25 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_graph_rejects_traversal_flags() {
+        let parse = |flag: &str| Args::try_parse_from(["txngraphs", "--token-graph", flag]);
+
+        assert!(parse("--min-amount=1").is_ok());
+        for flag in [
+            "--temporal",
+            "--skip-contracts",
+            "--hub-threshold=10",
+            "--hub-multiplier=2",
+            "--parallel",
+            "--progress",
+        ] {
+            assert!(parse(flag).is_err(), "{} was accepted", flag);
+        }
+    }
+}
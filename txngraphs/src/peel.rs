@@ -0,0 +1,354 @@
+use crate::summary::decimal;
+use crate::types::TransferGraph;
+use alloy_primitives::{
+    Address,
+    aliases::{BlockNumber, U256},
+};
+use petgraph::Direction;
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use petgraph::visit::EdgeRef;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+
+/// PeelOptions
+///
+/// What a peel hop looks like for `find_peel_chains`: after receiving a token, a wallet
+/// sends at least `min_forward_ratio` of what it sends on to a fresh wallet, whose first
+/// transfer in the graph this is, and the rest in one to `max_peels` smaller transfers to
+/// side addresses.
+#[derive(Debug, Clone)]
+pub struct PeelOptions {
+    /// Fewest hops for a chain to be reported. Defaults to 3.
+    pub min_length: usize,
+    /// Defaults to 0.8.
+    pub min_forward_ratio: f64,
+    /// Defaults to 2.
+    pub max_peels: usize,
+}
+
+impl Default for PeelOptions {
+    fn default() -> Self {
+        Self {
+            min_length: 3,
+            min_forward_ratio: 0.8,
+            max_peels: 2,
+        }
+    }
+}
+
+impl PeelOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_min_length(self, min_length: usize) -> Self {
+        Self { min_length, ..self }
+    }
+
+    pub fn with_min_forward_ratio(self, min_forward_ratio: f64) -> Self {
+        Self {
+            min_forward_ratio,
+            ..self
+        }
+    }
+
+    pub fn with_max_peels(self, max_peels: usize) -> Self {
+        Self { max_peels, ..self }
+    }
+}
+
+/// Peel
+///
+/// One amount peeled off a chain to a side address.
+#[derive(Debug, Clone, Serialize)]
+pub struct Peel {
+    pub from: Address,
+    pub to: Address,
+    #[serde(serialize_with = "decimal")]
+    pub amount: U256,
+    pub edge: EdgeIndex,
+}
+
+/// PeelChain
+///
+/// Wallets that each forwarded most of a token to the next, fresh, wallet and peeled a little
+/// off. `wallets` runs from the first peeling wallet to the one left holding the remainder, so
+/// it has one more entry than `forwards`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeelChain {
+    pub token: Address,
+    pub wallets: Vec<Address>,
+    pub forwards: Vec<EdgeIndex>,
+    pub peels: Vec<Peel>,
+    #[serde(serialize_with = "decimal")]
+    pub total_peeled: U256,
+    /// What the last wallet was sent, i.e. what's left after peeling.
+    #[serde(serialize_with = "decimal")]
+    pub remainder: U256,
+    pub first_block: BlockNumber,
+    pub last_block: BlockNumber,
+}
+
+impl PeelChain {
+    /// Number of peel hops.
+    pub fn len(&self) -> usize {
+        self.forwards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.forwards.is_empty()
+    }
+
+    /// Addresses that received peels, once each, in the order they were first paid.
+    pub fn peel_destinations(&self) -> Vec<Address> {
+        let mut seen = HashSet::new();
+        self.peels
+            .iter()
+            .map(|peel| peel.to)
+            .filter(|to| seen.insert(*to))
+            .collect()
+    }
+}
+
+impl Display for PeelChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Peel chain of {} hops from {:.36} in blocks {}..={}: peeled {} of {:.36} to {} \
+             addresses, {} left at {:.36}",
+            self.len(),
+            self.wallets[0],
+            self.first_block,
+            self.last_block,
+            self.total_peeled,
+            self.token,
+            self.peel_destinations().len(),
+            self.remainder,
+            self.wallets[self.wallets.len() - 1]
+        )?;
+        for peel in &self.peels {
+            writeln!(
+                f,
+                "  {:.36} -> {:.36} peeled {}",
+                peel.from, peel.to, peel.amount
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// The forward and peels a wallet made after receiving at `after`
+struct PeelHop {
+    forward: EdgeIndex,
+    peels: Vec<EdgeIndex>,
+}
+
+// Whether `node`'s outgoing transfers of `token` after `after` make a peel hop
+fn peel_hop(
+    graph: &TransferGraph,
+    node: NodeIndex,
+    token: Address,
+    after: Option<(BlockNumber, u64)>,
+    options: &PeelOptions,
+) -> Option<PeelHop> {
+    let mut outgoing: Vec<EdgeIndex> = graph
+        .edges_directed(node, Direction::Outgoing)
+        .filter(|edge| {
+            edge.target() != node
+                && edge.weight().token == token
+                && after.is_none_or(|after| edge.weight().position() > after)
+        })
+        .map(|edge| edge.id())
+        .collect();
+    if outgoing.len() < 2 || outgoing.len() > options.max_peels + 1 {
+        return None;
+    }
+    outgoing.sort_by_key(|idx| graph[*idx].position());
+
+    let forward = *outgoing.iter().max_by_key(|idx| {
+        (
+            graph[**idx].amount,
+            std::cmp::Reverse(graph[**idx].position()),
+        )
+    })?;
+    let total = outgoing.iter().fold(U256::ZERO, |total, idx| {
+        total.saturating_add(graph[*idx].amount)
+    });
+    if f64::from(graph[forward].amount) < options.min_forward_ratio * f64::from(total) {
+        return None;
+    }
+
+    // The next wallet must be fresh: nothing reached it before the forward
+    let next = graph.edge_endpoints(forward)?.1;
+    let first_in = graph
+        .edges_directed(next, Direction::Incoming)
+        .map(|edge| edge.weight().position())
+        .min()?;
+    if first_in < graph[forward].position() {
+        return None;
+    }
+
+    Some(PeelHop {
+        forward,
+        peels: outgoing.into_iter().filter(|idx| *idx != forward).collect(),
+    })
+}
+
+/// Peel chains of at least `options.min_length` hops in `graph`, longest first.
+///
+/// Hops are matched on time-ordered transfers of one token: a wallet's forward and peels
+/// must come after the transfer that brought it the funds.
+pub fn find_peel_chains(graph: &TransferGraph, options: &PeelOptions) -> Vec<PeelChain> {
+    // Every wallet's peel hop per token, counted from its first receipt of that token
+    let mut hops: BTreeMap<(Address, NodeIndex), PeelHop> = BTreeMap::new();
+    for node in graph.node_indices() {
+        let mut tokens: Vec<Address> = graph
+            .edges_directed(node, Direction::Outgoing)
+            .map(|edge| edge.weight().token)
+            .collect();
+        tokens.sort();
+        tokens.dedup();
+
+        for token in tokens {
+            let entry = graph
+                .edges_directed(node, Direction::Incoming)
+                .filter(|edge| edge.weight().token == token)
+                .map(|edge| edge.weight().position())
+                .min();
+            if let Some(hop) = peel_hop(graph, node, token, entry, options) {
+                hops.insert((token, node), hop);
+            }
+        }
+    }
+
+    // Chains start at wallets that weren't forwarded to by another hop
+    let continued: HashSet<(Address, NodeIndex)> = hops
+        .iter()
+        .map(|((token, _), hop)| (*token, graph.edge_endpoints(hop.forward).unwrap().1))
+        .collect();
+
+    let mut chains = Vec::new();
+    for (token, start) in hops.keys() {
+        if continued.contains(&(*token, *start)) {
+            continue;
+        }
+        let mut wallets = vec![graph[*start].address];
+        let mut forwards = Vec::new();
+        let mut peels = Vec::new();
+        let mut node = *start;
+        while let Some(hop) = hops.get(&(*token, node)) {
+            forwards.push(hop.forward);
+            peels.extend(hop.peels.iter().map(|idx| Peel {
+                from: graph[node].address,
+                to: graph[graph.edge_endpoints(*idx).unwrap().1].address,
+                amount: graph[*idx].amount,
+                edge: *idx,
+            }));
+            node = graph.edge_endpoints(hop.forward).unwrap().1;
+            wallets.push(graph[node].address);
+        }
+        if forwards.len() < options.min_length {
+            continue;
+        }
+
+        let blocks = forwards
+            .iter()
+            .chain(peels.iter().map(|peel| &peel.edge))
+            .map(|idx| graph[*idx].block_number);
+        chains.push(PeelChain {
+            token: *token,
+            total_peeled: peels
+                .iter()
+                .fold(U256::ZERO, |total, peel| total.saturating_add(peel.amount)),
+            remainder: graph[*forwards.last().unwrap()].amount,
+            first_block: blocks.clone().min().unwrap_or_default(),
+            last_block: blocks.max().unwrap_or_default(),
+            wallets,
+            forwards,
+            peels,
+        });
+    }
+    chains.sort_by(|a, b| {
+        b.len()
+            .cmp(&a.len())
+            .then(a.first_block.cmp(&b.first_block))
+    });
+    chains
+}
+
+/// Set `NodeFlags::peel` on the wallets of `chains`, so the DOT writers outline them.
+pub fn mark_peel_chains(graph: &mut TransferGraph, chains: &[PeelChain]) {
+    let wallets: HashSet<Address> = chains
+        .iter()
+        .flat_map(|chain| chain.wallets.iter().copied())
+        .collect();
+    for node in graph.node_weights_mut() {
+        if wallets.contains(&node.address) {
+            node.flags.peel = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::types::Transfer;
+
+    // 1 funds 2, and 2, 3 and 4 each forward most of it on and peel 10 off to a side address
+    fn chain_transfers() -> Vec<Transfer> {
+        vec![
+            transfer(1, 2, 1, 0, 100),
+            transfer(2, 3, 2, 0, 90),
+            transfer(2, 10, 2, 1, 10),
+            transfer(3, 11, 3, 0, 10),
+            transfer(3, 4, 3, 1, 80),
+            transfer(4, 5, 4, 0, 70),
+            transfer(4, 12, 4, 1, 10),
+        ]
+    }
+
+    #[test]
+    fn finds_a_peel_chain() {
+        let graph = graph_of(&chain_transfers());
+        let chains = find_peel_chains(&graph, &PeelOptions::new());
+
+        assert_eq!(chains.len(), 1);
+        let chain = &chains[0];
+        assert_eq!(
+            chain.wallets,
+            vec![address(2), address(3), address(4), address(5)]
+        );
+        assert_eq!(
+            chain.peel_destinations(),
+            vec![address(10), address(11), address(12)]
+        );
+        assert_eq!(chain.total_peeled, U256::from(30));
+        assert_eq!(chain.remainder, U256::from(70));
+        assert_eq!((chain.first_block, chain.last_block), (2, 4));
+    }
+
+    #[test]
+    fn chain_stops_at_a_wallet_that_isnt_fresh() {
+        // 5 was paid before 4 forwarded to it, so the chain is only two hops
+        let mut transfers = chain_transfers();
+        transfers.push(transfer(9, 5, 1, 1, 1));
+        let graph = graph_of(&transfers);
+
+        assert!(find_peel_chains(&graph, &PeelOptions::new()).is_empty());
+        let chains = find_peel_chains(&graph, &PeelOptions::new().with_min_length(2));
+        assert_eq!(chains[0].wallets, vec![address(2), address(3), address(4)]);
+    }
+
+    #[test]
+    fn marks_chain_wallets() {
+        let mut graph = graph_of(&chain_transfers());
+        let chains = find_peel_chains(&graph, &PeelOptions::new());
+        mark_peel_chains(&mut graph, &chains);
+
+        let peel = |n| graph.node(&address(n)).unwrap().flags.peel;
+        assert!([2, 3, 4, 5].into_iter().all(peel));
+        assert!(![1, 10, 11, 12].into_iter().any(peel));
+    }
+}
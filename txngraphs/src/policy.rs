@@ -0,0 +1,338 @@
+use crate::{data_sources::TransferDataSource, types::Transfer};
+use alloy_primitives::{Address, aliases::U256};
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+
+/// ExpansionPolicy
+///
+/// Decides, during traversal, which transfers make it into the graph and which discovered
+/// addresses get expanded in turn. Both checks default to `true`, so a policy only needs to
+/// implement the one it cares about, and say so with `filters_edges` or `filters_nodes`.
+///
+/// Policies compose with `and` / `or`, e.g.
+/// `MinAmount::new(dust).and(NoSelfTransfers).and(NoContractExpansion)`.
+pub trait ExpansionPolicy: Send + Sync {
+    /// Should this transfer be added to the graph?
+    fn include_edge(&self, _transfer: &Transfer) -> bool {
+        true
+    }
+
+    /// Should `address`, first discovered at `depth`, be queried for its own transfers?
+    ///
+    /// Nodes that aren't expanded stay in the graph as leaves.
+    fn expand_node(
+        &self,
+        _address: &Address,
+        _depth: usize,
+        _data_source: &dyn TransferDataSource,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Whether the policy implements `include_edge`. `AnyOf` only asks the policies that do,
+    /// so that one which only limits expansion doesn't let every transfer through.
+    fn filters_edges(&self) -> bool {
+        false
+    }
+
+    /// Whether the policy implements `expand_node`, for the same reason as `filters_edges`.
+    fn filters_nodes(&self) -> bool {
+        false
+    }
+
+    fn and<P: ExpansionPolicy + 'static>(self, other: P) -> AllOf
+    where
+        Self: Sized + 'static,
+    {
+        AllOf(vec![Box::new(self), Box::new(other)])
+    }
+
+    fn or<P: ExpansionPolicy + 'static>(self, other: P) -> AnyOf
+    where
+        Self: Sized + 'static,
+    {
+        AnyOf(vec![Box::new(self), Box::new(other)])
+    }
+}
+
+/// AllOf
+///
+/// Includes an edge / expands a node only if every inner policy agrees.
+pub struct AllOf(pub Vec<Box<dyn ExpansionPolicy>>);
+
+impl ExpansionPolicy for AllOf {
+    fn include_edge(&self, transfer: &Transfer) -> bool {
+        self.0.iter().all(|policy| policy.include_edge(transfer))
+    }
+
+    fn expand_node(
+        &self,
+        address: &Address,
+        depth: usize,
+        data_source: &dyn TransferDataSource,
+    ) -> Result<bool> {
+        for policy in &self.0 {
+            if !policy.expand_node(address, depth, data_source)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn filters_edges(&self) -> bool {
+        self.0.iter().any(|policy| policy.filters_edges())
+    }
+
+    fn filters_nodes(&self) -> bool {
+        self.0.iter().any(|policy| policy.filters_nodes())
+    }
+
+    // Flatten chains like a.and(b).and(c) instead of nesting
+    fn and<P: ExpansionPolicy + 'static>(mut self, other: P) -> AllOf {
+        self.0.push(Box::new(other));
+        self
+    }
+}
+
+/// AnyOf
+///
+/// Includes an edge / expands a node if at least one inner policy does. Only the policies that
+/// filter edges (or nodes) get a say, e.g. in `MinAmount::new(dust).or(TerminalAddresses(..))`
+/// dust is dropped and the terminals aren't expanded. With none that do, everything passes.
+pub struct AnyOf(pub Vec<Box<dyn ExpansionPolicy>>);
+
+impl ExpansionPolicy for AnyOf {
+    fn include_edge(&self, transfer: &Transfer) -> bool {
+        let mut filtering = self
+            .0
+            .iter()
+            .filter(|policy| policy.filters_edges())
+            .peekable();
+        filtering.peek().is_none() || filtering.any(|policy| policy.include_edge(transfer))
+    }
+
+    fn expand_node(
+        &self,
+        address: &Address,
+        depth: usize,
+        data_source: &dyn TransferDataSource,
+    ) -> Result<bool> {
+        let mut asked = false;
+        for policy in self.0.iter().filter(|policy| policy.filters_nodes()) {
+            if policy.expand_node(address, depth, data_source)? {
+                return Ok(true);
+            }
+            asked = true;
+        }
+        Ok(!asked)
+    }
+
+    fn filters_edges(&self) -> bool {
+        self.0.iter().any(|policy| policy.filters_edges())
+    }
+
+    fn filters_nodes(&self) -> bool {
+        self.0.iter().any(|policy| policy.filters_nodes())
+    }
+
+    fn or<P: ExpansionPolicy + 'static>(mut self, other: P) -> AnyOf {
+        self.0.push(Box::new(other));
+        self
+    }
+}
+
+/// MinAmount
+///
+/// Drops dust: transfers below a raw amount threshold, optionally set per token since
+/// decimals differ.
+pub struct MinAmount {
+    pub default: U256,
+    pub per_token: HashMap<Address, U256>,
+}
+
+impl MinAmount {
+    pub fn new(default: U256) -> Self {
+        Self {
+            default,
+            per_token: HashMap::new(),
+        }
+    }
+
+    pub fn with_token_threshold(mut self, token: Address, min_amount: U256) -> Self {
+        self.per_token.insert(token, min_amount);
+        self
+    }
+}
+
+impl ExpansionPolicy for MinAmount {
+    fn include_edge(&self, transfer: &Transfer) -> bool {
+        let min_amount = self.per_token.get(&transfer.token).unwrap_or(&self.default);
+        transfer.amount >= *min_amount
+    }
+
+    fn filters_edges(&self) -> bool {
+        true
+    }
+}
+
+/// ExcludeAddresses
+///
+/// Drops every transfer to or from the listed addresses.
+pub struct ExcludeAddresses(pub HashSet<Address>);
+
+impl ExpansionPolicy for ExcludeAddresses {
+    fn include_edge(&self, transfer: &Transfer) -> bool {
+        !self.0.contains(&transfer.from_address) && !self.0.contains(&transfer.to_address)
+    }
+
+    fn filters_edges(&self) -> bool {
+        true
+    }
+}
+
+/// TerminalAddresses
+///
+/// Keeps transfers into the listed addresses but never expands them, e.g. known exchange
+/// deposit addresses where the trail is expected to end.
+pub struct TerminalAddresses(pub HashSet<Address>);
+
+impl ExpansionPolicy for TerminalAddresses {
+    fn expand_node(
+        &self,
+        address: &Address,
+        _depth: usize,
+        _data_source: &dyn TransferDataSource,
+    ) -> Result<bool> {
+        Ok(!self.0.contains(address))
+    }
+
+    fn filters_nodes(&self) -> bool {
+        true
+    }
+}
+
+/// NoZeroAddress
+///
+/// Drops mints and burns, i.e. transfers from or to the zero address.
+pub struct NoZeroAddress;
+
+impl ExpansionPolicy for NoZeroAddress {
+    fn include_edge(&self, transfer: &Transfer) -> bool {
+        !transfer.from_address.is_zero() && !transfer.to_address.is_zero()
+    }
+
+    fn filters_edges(&self) -> bool {
+        true
+    }
+}
+
+/// NoSelfTransfers
+///
+/// Drops transfers where sender and recipient are the same address.
+pub struct NoSelfTransfers;
+
+impl ExpansionPolicy for NoSelfTransfers {
+    fn include_edge(&self, transfer: &Transfer) -> bool {
+        transfer.from_address != transfer.to_address
+    }
+
+    fn filters_edges(&self) -> bool {
+        true
+    }
+}
+
+/// NoContractExpansion
+///
+/// Keeps transfers into contracts but doesn't expand them, so routers and pools don't pull
+/// in every one of their counterparties. Relies on `TransferDataSource::is_contract`.
+pub struct NoContractExpansion;
+
+impl ExpansionPolicy for NoContractExpansion {
+    fn expand_node(
+        &self,
+        address: &Address,
+        _depth: usize,
+        data_source: &dyn TransferDataSource,
+    ) -> Result<bool> {
+        Ok(!data_source.is_contract(address)?)
+    }
+
+    fn filters_nodes(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn expands(policy: &dyn ExpansionPolicy, source: &MemorySource, n: u8) -> bool {
+        policy.expand_node(&address(n), 1, source).unwrap()
+    }
+
+    #[test]
+    fn any_of_only_asks_policies_that_filter() {
+        let source = MemorySource::default();
+        let policy =
+            MinAmount::new(U256::from(10)).or(TerminalAddresses(HashSet::from([address(3)])));
+
+        // TerminalAddresses has no say on edges, nor MinAmount on nodes
+        assert!(!policy.include_edge(&transfer(1, 2, 1, 0, 5)));
+        assert!(policy.include_edge(&transfer(1, 3, 1, 0, 10)));
+        assert!(!expands(&policy, &source, 3));
+        assert!(expands(&policy, &source, 2));
+    }
+
+    #[test]
+    fn any_of_passes_a_transfer_one_filter_accepts() {
+        let policy = MinAmount::new(U256::from(10)).or(NoSelfTransfers);
+
+        assert!(policy.include_edge(&transfer(1, 2, 1, 0, 5)));
+        assert!(policy.include_edge(&transfer(1, 1, 1, 0, 10)));
+        assert!(!policy.include_edge(&transfer(1, 1, 1, 0, 5)));
+    }
+
+    #[test]
+    fn any_of_without_filters_passes_everything() {
+        let mut source = MemorySource::default();
+        source.contracts.insert(address(2));
+        let edges_only = NoZeroAddress.or(NoSelfTransfers);
+        let nodes_only = NoContractExpansion.or(TerminalAddresses(HashSet::new()));
+
+        assert!(expands(&edges_only, &source, 2));
+        assert!(nodes_only.include_edge(&transfer(1, 1, 1, 0, 5)));
+        // TerminalAddresses is happy to expand the contract
+        assert!(expands(&nodes_only, &source, 2));
+    }
+
+    #[test]
+    fn all_of_needs_every_policy_and_flattens() {
+        let mut source = MemorySource::default();
+        source.contracts.insert(address(2));
+        let policy = MinAmount::new(U256::from(10))
+            .and(NoSelfTransfers)
+            .and(NoContractExpansion);
+
+        assert_eq!(policy.0.len(), 3);
+        assert!(policy.include_edge(&transfer(1, 2, 1, 0, 10)));
+        assert!(!policy.include_edge(&transfer(1, 2, 1, 0, 5)));
+        assert!(!policy.include_edge(&transfer(1, 1, 1, 0, 10)));
+        assert!(!expands(&policy, &source, 2));
+        assert!(expands(&policy, &source, 3));
+    }
+
+    #[test]
+    fn nested_combinators_report_what_they_filter() {
+        let source = MemorySource::default();
+        let terminals = TerminalAddresses(HashSet::from([address(3)]));
+        let policy = AnyOf(vec![
+            Box::new(MinAmount::new(U256::from(10)).and(terminals)),
+            Box::new(NoSelfTransfers),
+        ]);
+
+        assert!(policy.filters_edges() && policy.filters_nodes());
+        assert!(!policy.include_edge(&transfer(1, 1, 1, 0, 5)));
+        assert!(!expands(&policy, &source, 3));
+    }
+}
@@ -0,0 +1,113 @@
+use alloy_primitives::{Address, aliases::BlockNumber};
+use std::fmt::Display;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+use std::time::{Duration, Instant};
+
+/// TraversalObserver
+///
+/// Progress hooks for long traversals. Every method defaults to a no-op.
+///
+/// Observers are called from rayon worker threads when tiers or chunks are fetched in
+/// parallel, hence `Send + Sync`.
+pub trait TraversalObserver: Send + Sync {
+    /// An address is about to be queried.
+    fn on_node_dequeued(&self, _address: &Address, _depth: usize) {}
+
+    /// An address's transfers came back from the data source.
+    fn on_transfers_fetched(&self, _address: &Address, _depth: usize, _count: usize) {}
+
+    /// Every address at `depth` has been merged into the graph.
+    fn on_depth_completed(&self, _depth: usize, _nodes: usize, _edges: usize) {}
+
+    /// The reth source finished scanning a block chunk.
+    fn on_chunk_scanned(
+        &self,
+        _start_block: BlockNumber,
+        _end_block: BlockNumber,
+        _matches: usize,
+    ) {
+    }
+}
+
+/// LogObserver
+///
+/// Reports progress through `tracing` at info level.
+pub struct LogObserver;
+
+impl TraversalObserver for LogObserver {
+    fn on_transfers_fetched(&self, address: &Address, depth: usize, count: usize) {
+        tracing::info!(
+            "Fetched {} transfers for {} at depth {}",
+            count,
+            address,
+            depth
+        );
+    }
+
+    fn on_depth_completed(&self, depth: usize, nodes: usize, edges: usize) {
+        tracing::info!(
+            "Finished depth {}: graph has {} nodes and {} edges",
+            depth,
+            nodes,
+            edges
+        );
+    }
+}
+
+/// CancellationToken
+///
+/// Shared stop flag with an optional deadline. Clones share the flag, so a token handed to the
+/// traversal and the data source can be cancelled from another thread, e.g. a Ctrl-C handler.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_deadline(self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// True once `cancel` has been called or the deadline has passed.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.is_timed_out()
+    }
+}
+
+/// Cancelled
+///
+/// Error returned by data sources that stopped early because their `CancellationToken` fired.
+#[derive(Debug, Clone, Copy)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
@@ -1,10 +1,20 @@
 use alloy_consensus::TxReceipt;
-use alloy_primitives::{Address, B256, U256, aliases::BlockNumber, b256};
+use alloy_primitives::{Address, B256, KECCAK256_EMPTY, U256, aliases::BlockNumber, b256};
 use anyhow::{Context, Result};
-use std::{path::Path, sync::Arc};
+use std::{
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
 use tracing::info;
 // Database components
-use crate::{data_sources::TransferDataSource, types::Transfer};
+use crate::{
+    data_sources::{TransferDataSource, token_matches},
+    progress::{CancellationToken, Cancelled, TraversalObserver},
+    types::Transfer,
+};
 use rayon::prelude::*;
 use reth_db::{DatabaseEnv, mdbx::DatabaseArguments, open_db_read_only};
 use reth_node_types::NodeTypesWithDBAdapter;
@@ -12,14 +22,40 @@ use reth_op::node::OpNode;
 use reth_optimism_chainspec::UNICHAIN_MAINNET;
 use reth_provider::providers::StaticFileProvider;
 use reth_provider::{
-    BlockBodyIndicesProvider, ProviderFactory, ReceiptProvider, TransactionsProvider,
+    AccountReader, BlockBodyIndicesProvider, ProviderFactory, ReceiptProvider, TransactionsProvider,
 };
 
 const ERC20_TRANSFER_EVENT_SIGNATURE: B256 =
     b256!("0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef");
 
+// A matched Transfer log before its tx hash is looked up:
+// (tx_num, block, log index, from, to, amount, token)
+type RawTransfer = (u64, BlockNumber, u64, Address, Address, U256, Address);
+
+// Matches counted across every chunk of one scan, so all of them stop once there are more
+// than `limit`
+struct MatchLimit {
+    matched: AtomicUsize,
+    limit: usize,
+}
+
+impl MatchLimit {
+    fn new(limit: usize) -> Self {
+        Self {
+            matched: AtomicUsize::new(0),
+            limit,
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.matched.load(Ordering::Relaxed) > self.limit
+    }
+}
+
 pub struct RethTransferDataSource {
     pub factory: ProviderFactory<NodeTypesWithDBAdapter<OpNode, Arc<DatabaseEnv>>>,
+    observer: Option<Arc<dyn TraversalObserver>>,
+    cancellation: Option<CancellationToken>,
 }
 
 impl RethTransferDataSource {
@@ -34,32 +70,79 @@ impl RethTransferDataSource {
             StaticFileProvider::read_only(db_path.join("static_files"), true).unwrap(),
         );
 
-        Self { factory }
+        Self {
+            factory,
+            observer: None,
+            cancellation: None,
+        }
     }
 
-    fn process_chunk(
-        factory: ProviderFactory<NodeTypesWithDBAdapter<OpNode, Arc<DatabaseEnv>>>,
-        address: Address,
-        token_addresses: Vec<Address>,
+    /// Report each scanned block chunk to `observer`.
+    pub fn with_observer(self, observer: Arc<dyn TraversalObserver>) -> Self {
+        Self {
+            observer: Some(observer),
+            ..self
+        }
+    }
+
+    /// Stop scanning, returning a `Cancelled` error, once `cancellation` fires.
+    pub fn with_cancellation(self, cancellation: CancellationToken) -> Self {
+        Self {
+            cancellation: Some(cancellation),
+            ..self
+        }
+    }
+
+    // Split an inclusive block range into non-overlapping chunks for parallel scans
+    fn chunk_range(
+        block_start: BlockNumber,
+        block_end: BlockNumber,
+    ) -> Vec<(BlockNumber, BlockNumber)> {
+        let chunk_size: u64 = 20000;
+        (block_start..=block_end)
+            .step_by(chunk_size as usize)
+            .map(|start| (start, std::cmp::min(start + chunk_size - 1, block_end)))
+            .collect()
+    }
+
+    // Scan receipts for matching Transfer logs without looking up tx hashes. With no sender,
+    // every transfer of the tokens matches. With a limit, the scan gives up early once it's
+    // exceeded, and what it found so far is incomplete.
+    fn scan_chunk(
+        &self,
+        sender: Option<Address>,
+        token_addresses: &[Address],
         start_block: BlockNumber,
         end_block: BlockNumber,
-    ) -> Result<Vec<Transfer>> {
+        limit: Option<&MatchLimit>,
+    ) -> Result<Vec<RawTransfer>> {
         info!(
             "starting block range {}, end block {}",
             start_block, end_block
         );
-        let mut transfers: Vec<Transfer> = Vec::new();
-        // I want to collect all txn data without the hash for efficiency so I need
-        // this intermediate vector
         let mut txns_no_hash = Vec::new();
-        let provider = factory.provider()?;
+        let provider = self.factory.provider()?;
 
         for bn in start_block..=end_block {
+            if self
+                .cancellation
+                .as_ref()
+                .is_some_and(|token| token.is_cancelled())
+            {
+                return Err(Cancelled.into());
+            }
+            if limit.is_some_and(|limit| limit.exceeded()) {
+                break;
+            }
+
             let txns_in_block = provider
                 .block_body_indices(bn)
                 .context("failed to get block body indices")?
                 .context(format!("No block body indices found for block {}", bn))?;
 
+            // log index is counted across every receipt in the block
+            let mut log_index: u64 = 0;
+
             for tx_num in txns_in_block.tx_num_range() {
                 let tx_receipt = provider
                     .receipt(tx_num)
@@ -68,24 +151,52 @@ impl RethTransferDataSource {
 
                 // check if tx is relevant
                 for log in tx_receipt.logs() {
-                    if token_addresses.contains(&log.address)
+                    if token_matches(token_addresses, &log.address)
                         && log.topics().len() == 3
                         && log.topics()[0] == ERC20_TRANSFER_EVENT_SIGNATURE
-                        && Address::from_word(log.topics()[1]) == address
+                        && sender.is_none_or(|sender| Address::from_word(log.topics()[1]) == sender)
                     {
                         let from = Address::from_word(log.topics()[1]);
                         let to = Address::from_word(log.topics()[2]);
                         let amount = U256::from_be_slice(&log.data.data);
 
-                        txns_no_hash.push((tx_num, bn, from, to, amount, log.address));
+                        txns_no_hash.push((tx_num, bn, log_index, from, to, amount, log.address));
+                        if let Some(limit) = limit {
+                            limit.matched.fetch_add(1, Ordering::Relaxed);
+                        }
                         info!(
                             "Pushed onto txns_no_hash: {:?}",
                             txns_no_hash.last().unwrap()
                         );
                     }
+                    log_index += 1;
                 }
             }
         }
+        if let Some(observer) = &self.observer {
+            observer.on_chunk_scanned(start_block, end_block, txns_no_hash.len());
+        }
+        Ok(txns_no_hash)
+    }
+
+    fn process_chunk(
+        &self,
+        sender: Option<Address>,
+        token_addresses: &[Address],
+        start_block: BlockNumber,
+        end_block: BlockNumber,
+    ) -> Result<Vec<Transfer>> {
+        // I want to collect all txn data without the hash for efficiency so I need
+        // this intermediate vector
+        let txns_no_hash =
+            self.scan_chunk(sender, token_addresses, start_block, end_block, None)?;
+        self.add_tx_hashes(txns_no_hash)
+    }
+
+    fn add_tx_hashes(&self, txns_no_hash: Vec<RawTransfer>) -> Result<Vec<Transfer>> {
+        let mut transfers: Vec<Transfer> = Vec::new();
+        let provider = self.factory.provider()?;
+
         // for matched txns, get the tx hash
         for txn in txns_no_hash {
             let tx_data = provider
@@ -96,10 +207,11 @@ impl RethTransferDataSource {
             transfers.push(Transfer {
                 tx_hash: *tx_data.hash(),
                 block_number: txn.1,
-                from_address: txn.2,
-                to_address: txn.3,
-                token: txn.5,
-                amount: txn.4,
+                log_index: txn.2,
+                from_address: txn.3,
+                to_address: txn.4,
+                token: txn.6,
+                amount: txn.5,
             });
 
             info!(
@@ -119,27 +231,141 @@ impl TransferDataSource for RethTransferDataSource {
         block_start: &BlockNumber,
         block_end: &BlockNumber,
     ) -> Result<Vec<Transfer>> {
-        let chunk_size: u64 = 20000;
-        let chunks: Vec<(BlockNumber, BlockNumber)> = (*block_start..=*block_end)
-            .step_by(chunk_size as usize)
-            .map(|start| (start, std::cmp::min(start + chunk_size, *block_end)))
-            .collect();
+        let chunks = Self::chunk_range(*block_start, *block_end);
 
         let all_transfers: Result<Vec<Vec<Transfer>>> = chunks
             .into_par_iter()
             .map(|(start_block, end_block)| {
-                Self::process_chunk(
-                    self.factory.clone(),
-                    *address,
-                    token_addresses.to_vec(),
+                self.process_chunk(Some(*address), token_addresses, start_block, end_block)
+            })
+            .collect();
+
+        let flattened: Vec<Transfer> = all_transfers?.into_iter().flatten().collect();
+
+        Ok(flattened)
+    }
+
+    fn get_token_transfers(
+        &self,
+        token_addresses: &[Address],
+        block_start: &BlockNumber,
+        block_end: &BlockNumber,
+    ) -> Result<Vec<Transfer>> {
+        let all_transfers: Result<Vec<Vec<Transfer>>> = Self::chunk_range(*block_start, *block_end)
+            .into_par_iter()
+            .map(|(start_block, end_block)| {
+                self.process_chunk(None, token_addresses, start_block, end_block)
+            })
+            .collect();
+
+        Ok(all_transfers?.into_iter().flatten().collect())
+    }
+
+    fn get_transfers_up_to(
+        &self,
+        address: &Address,
+        token_addresses: &[Address],
+        block_start: &BlockNumber,
+        block_end: &BlockNumber,
+        limit: usize,
+    ) -> Result<Option<Vec<Transfer>>> {
+        // Scan first and only look up tx hashes once we know the address isn't a hub
+        let limit = MatchLimit::new(limit);
+        let scanned: Result<Vec<Vec<RawTransfer>>> = Self::chunk_range(*block_start, *block_end)
+            .into_par_iter()
+            .map(|(start_block, end_block)| {
+                self.scan_chunk(
+                    Some(*address),
+                    token_addresses,
                     start_block,
                     end_block,
+                    Some(&limit),
                 )
             })
             .collect();
+        let scanned = scanned?;
+        if limit.exceeded() {
+            return Ok(None);
+        }
 
-        let flattened: Vec<Transfer> = all_transfers?.into_iter().flatten().collect();
+        let transfers: Result<Vec<Vec<Transfer>>> = scanned
+            .into_par_iter()
+            .map(|txns_no_hash| self.add_tx_hashes(txns_no_hash))
+            .collect();
+        Ok(Some(transfers?.into_iter().flatten().collect()))
+    }
 
-        Ok(flattened)
+    fn get_transaction_transfers(
+        &self,
+        tx_hash: &B256,
+        token_addresses: &[Address],
+    ) -> Result<Vec<Transfer>> {
+        let provider = self.factory.provider()?;
+
+        // tx hash -> tx number goes through the TransactionHashNumbers table
+        let tx_num = provider
+            .transaction_id(*tx_hash)
+            .context("failed to look up tx hash")?
+            .context(format!("No transaction found for hash {}", tx_hash))?;
+        let bn = provider
+            .transaction_block(tx_num)
+            .context("failed to get transaction block")?
+            .context(format!("No block found for tx_num {:?}", tx_num))?;
+        let txns_in_block = provider
+            .block_body_indices(bn)
+            .context("failed to get block body indices")?
+            .context(format!("No block body indices found for block {}", bn))?;
+
+        // log index is counted across every receipt in the block, so skip past earlier txs' logs
+        let mut log_index: u64 = 0;
+        for earlier_tx_num in txns_in_block.first_tx_num()..tx_num {
+            let tx_receipt = provider
+                .receipt(earlier_tx_num)
+                .context("failed to get tx receipt")?
+                .context(format!(
+                    "No tx receipt found for tx_num {:?}",
+                    earlier_tx_num
+                ))?;
+            log_index += tx_receipt.logs().len() as u64;
+        }
+
+        let tx_receipt = provider
+            .receipt(tx_num)
+            .context("failed to get tx receipt")?
+            .context(format!("No tx receipt found for tx_num {:?}", tx_num))?;
+
+        let mut transfers = Vec::new();
+        for log in tx_receipt.logs() {
+            if token_matches(token_addresses, &log.address)
+                && log.topics().len() == 3
+                && log.topics()[0] == ERC20_TRANSFER_EVENT_SIGNATURE
+            {
+                transfers.push(Transfer {
+                    tx_hash: *tx_hash,
+                    block_number: bn,
+                    log_index,
+                    from_address: Address::from_word(log.topics()[1]),
+                    to_address: Address::from_word(log.topics()[2]),
+                    token: log.address,
+                    amount: U256::from_be_slice(&log.data.data),
+                });
+            }
+            log_index += 1;
+        }
+        Ok(transfers)
+    }
+
+    fn is_contract(&self, address: &Address) -> Result<bool> {
+        // Checked against the latest state, so self-destructed contracts read as EOAs
+        let account = self
+            .factory
+            .latest()
+            .context("failed to open latest state")?
+            .basic_account(address)
+            .context("failed to get account")?;
+
+        Ok(account
+            .and_then(|account| account.bytecode_hash)
+            .is_some_and(|hash| hash != KECCAK256_EMPTY))
     }
 }
@@ -0,0 +1,399 @@
+use crate::{
+    data_sources::TransferDataSource,
+    traversal::{
+        Fetched, GraphBuilder, TraversalOptions, TraversalStatus, TruncatedNode, flag_nodes,
+    },
+    types::*,
+};
+use alloy_primitives::{Address, BlockNumber};
+use anyhow::{Result, bail};
+use petgraph::graph::NodeIndex;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+/// TraversalSession
+///
+/// A transfer graph you can keep growing: expand a single node a few more hops, extend the
+/// block range, or prune addresses you've ruled out, without rebuilding from the root.
+///
+/// The session owns the data source and the graph state, and applies its `TraversalOptions`
+/// (temporal window, policy, hub cutoff, parallel fetching, cancellation) to every call.
+/// Addresses that have been expanded are never queried twice for the same blocks.
+pub struct TraversalSession<D: TransferDataSource> {
+    data_source: D,
+    root_address: Address,
+    block_start: BlockNumber,
+    block_end: BlockNumber,
+    token_addresses: Vec<Address>,
+    options: TraversalOptions,
+    graph: TransferGraph,
+    arrivals: HashMap<Address, (BlockNumber, u64)>,
+    truncated: Vec<TruncatedNode>,
+    expanded_counts: Vec<usize>,
+    // expanded holds the addresses whose transfers have been fetched over the whole block range
+    expanded: HashSet<Address>,
+    // pruned holds addresses removed by the user, which later expansions leave out
+    pruned: HashSet<Address>,
+}
+
+impl<D: TransferDataSource> TraversalSession<D> {
+    /// Start a session whose graph holds only `root_address`. Call `expand` to fetch anything.
+    pub fn new(
+        data_source: D,
+        root_address: Address,
+        block_start: BlockNumber,
+        block_end: BlockNumber,
+        token_addresses: Vec<Address>,
+    ) -> Self {
+        let mut graph = TransferGraph::new();
+        graph.add_node(
+            TransferNode::new(root_address)
+                .with_depth(0)
+                .with_root(root_address),
+        );
+
+        Self {
+            data_source,
+            root_address,
+            block_start,
+            block_end,
+            token_addresses,
+            options: TraversalOptions::default(),
+            graph,
+            arrivals: HashMap::new(),
+            truncated: Vec::new(),
+            expanded_counts: Vec::new(),
+            expanded: HashSet::new(),
+            pruned: HashSet::new(),
+        }
+    }
+
+    pub fn with_options(self, options: TraversalOptions) -> Self {
+        Self { options, ..self }
+    }
+
+    pub fn graph(&self) -> &TransferGraph {
+        &self.graph
+    }
+
+    pub fn into_graph(self) -> TransferGraph {
+        self.graph
+    }
+
+    pub fn node_index(&self, address: &Address) -> Option<NodeIndex> {
+        self.graph.node_index(address)
+    }
+
+    pub fn block_range(&self) -> (BlockNumber, BlockNumber) {
+        (self.block_start, self.block_end)
+    }
+
+    /// Hubs found so far that were kept as unexpanded terminals.
+    pub fn truncated(&self) -> &[TruncatedNode] {
+        &self.truncated
+    }
+
+    pub fn is_expanded(&self, address: &Address) -> bool {
+        self.expanded.contains(address)
+    }
+
+    /// Expand `address` and the addresses it paid out to `depth` hops away, as `max_depth` does
+    /// for `build_transfer_graph`. The address is added to the graph first if needed.
+    ///
+    /// Depths of new nodes count on from the depth `address` already has, or from 0 if it has
+    /// none, and only the session root is exempt from the hub cutoff. Addresses expanded by
+    /// earlier calls are walked through the existing graph instead of being queried again.
+    /// Expanding a pruned address un-prunes it.
+    pub fn expand(&mut self, address: Address, depth: usize) -> Result<TraversalStatus> {
+        self.pruned.remove(&address);
+        self.graph
+            .add_node(TransferNode::new(address).with_root(self.root_address));
+        let start = self.depth_of(&address);
+
+        // Hops are counted from the session root, so the builder stops at start + depth
+        let mut builder = GraphBuilder::new(
+            &self.data_source,
+            self.root_address,
+            self.block_start,
+            self.block_end,
+            &self.token_addresses,
+            start + depth,
+            &self.options,
+        );
+        builder.graph = mem::take(&mut self.graph);
+        builder.visited = HashSet::from([address]);
+        builder.arrivals = mem::take(&mut self.arrivals);
+        builder.truncated = mem::take(&mut self.truncated);
+        builder.expanded_counts = mem::take(&mut self.expanded_counts);
+
+        // Run the tiers in a closure so the state goes back into the session even on error
+        let mut run = || -> Result<TraversalStatus> {
+            let mut tier = vec![address];
+
+            for hop in start..=start + depth {
+                if tier.is_empty() {
+                    break;
+                }
+
+                let (known, unknown): (Vec<Address>, Vec<Address>) = tier
+                    .into_iter()
+                    .partition(|address| self.expanded.contains(address));
+
+                let fetched: Vec<Result<Fetched>> = if self.options.parallel {
+                    unknown
+                        .par_iter()
+                        .map(|address| builder.fetch_raw(*address, hop))
+                        .collect()
+                } else {
+                    unknown
+                        .iter()
+                        .map(|address| builder.fetch_raw(*address, hop))
+                        .collect()
+                };
+
+                let mut next_tier = Vec::new();
+
+                // Already expanded, so their recipients are in the graph
+                if hop < start + depth {
+                    for address in known {
+                        for recipient in builder.graph.neighbors_out(&address) {
+                            if !builder.visited.insert(recipient) {
+                                continue;
+                            }
+                            let expand = self.expanded.contains(&recipient)
+                                || match &self.options.policy {
+                                    Some(policy) => policy.expand_node(
+                                        &recipient,
+                                        hop + 1,
+                                        &self.data_source,
+                                    )?,
+                                    None => true,
+                                };
+                            if expand {
+                                next_tier.push(recipient);
+                            }
+                        }
+                    }
+                }
+
+                for (address, fetched) in unknown.into_iter().zip(fetched) {
+                    let fetched = match fetched {
+                        Ok(fetched) => fetched,
+                        Err(e) => return self.options.stop_on_cancel(e),
+                    };
+                    let Some(mut transfers) = builder.accept(address, hop, fetched) else {
+                        continue;
+                    };
+                    transfers.retain(|transfer| !self.pruned.contains(&transfer.to_address));
+                    let (_, discovered) = builder.add_transfers(hop, transfers)?;
+                    self.expanded.insert(address);
+                    next_tier.extend(discovered);
+                }
+                tier = next_tier;
+
+                if let Some(observer) = &self.options.observer {
+                    observer.on_depth_completed(
+                        hop,
+                        builder.graph.node_count(),
+                        builder.graph.edge_count(),
+                    );
+                }
+            }
+            Ok(TraversalStatus::Complete)
+        };
+        let status = run();
+
+        self.graph = builder.graph;
+        self.arrivals = builder.arrivals;
+        self.truncated = builder.truncated;
+        self.expanded_counts = builder.expanded_counts;
+        flag_nodes(&mut self.graph, &self.expanded, &self.truncated);
+        status
+    }
+
+    /// Move the end of the block range out to `block_end`, fetching the new blocks for every
+    /// expanded address.
+    ///
+    /// New recipients are added as leaves; `expand` them to follow the funds further. If the
+    /// session is cancelled part way, nothing is added and the range is left as it was.
+    pub fn extend_range(&mut self, block_end: BlockNumber) -> Result<TraversalStatus> {
+        if block_end <= self.block_end {
+            return Ok(TraversalStatus::Complete);
+        }
+
+        // Sorted so that the graph doesn't depend on HashSet order
+        let mut expanded: Vec<Address> = self.expanded.iter().copied().collect();
+        expanded.sort();
+
+        let depths: Vec<usize> = expanded
+            .iter()
+            .map(|address| self.depth_of(address))
+            .collect();
+
+        // max_depth 0, so add_transfers doesn't queue anything for expansion
+        let mut builder = GraphBuilder::new(
+            &self.data_source,
+            self.root_address,
+            self.block_end + 1,
+            block_end,
+            &self.token_addresses,
+            0,
+            &self.options,
+        );
+        builder.graph = mem::take(&mut self.graph);
+        builder.arrivals = mem::take(&mut self.arrivals);
+        builder.truncated = mem::take(&mut self.truncated);
+        builder.expanded_counts = mem::take(&mut self.expanded_counts);
+
+        let fetched: Result<Vec<Fetched>> = if self.options.parallel {
+            expanded
+                .par_iter()
+                .zip(&depths)
+                .map(|(address, depth)| builder.fetch_raw(*address, *depth))
+                .collect()
+        } else {
+            expanded
+                .iter()
+                .zip(&depths)
+                .map(|(address, depth)| builder.fetch_raw(*address, *depth))
+                .collect()
+        };
+
+        let merge = || -> Result<TraversalStatus> {
+            let fetched = match fetched {
+                Ok(fetched) => fetched,
+                Err(e) => return self.options.stop_on_cancel(e),
+            };
+            for ((address, depth), fetched) in expanded.into_iter().zip(depths).zip(fetched) {
+                let Some(mut transfers) = builder.accept(address, depth, fetched) else {
+                    continue;
+                };
+                transfers.retain(|transfer| !self.pruned.contains(&transfer.to_address));
+                builder.add_transfers(depth, transfers)?;
+            }
+            self.block_end = block_end;
+            Ok(TraversalStatus::Complete)
+        };
+        let status = merge();
+
+        self.graph = builder.graph;
+        self.arrivals = builder.arrivals;
+        self.truncated = builder.truncated;
+        self.expanded_counts = builder.expanded_counts;
+        flag_nodes(&mut self.graph, &self.expanded, &self.truncated);
+        status
+    }
+
+    /// Remove `address` and its transfers from the graph, along with any counterparties left
+    /// with no transfers at all. Later expansions won't add it back unless it's expanded
+    /// directly. Returns the number of nodes removed.
+    pub fn prune(&mut self, address: Address) -> Result<usize> {
+        if address == self.root_address {
+            bail!("Can't prune the session root {}", address);
+        }
+        self.pruned.insert(address);
+        let Some(idx) = self.node_index(&address) else {
+            return Ok(0);
+        };
+
+        let mut counterparties: Vec<Address> = self
+            .graph
+            .neighbors_undirected(idx)
+            .map(|idx| self.graph[idx].address)
+            .filter(|counterparty| *counterparty != address)
+            .collect();
+        counterparties.sort();
+        counterparties.dedup();
+
+        self.remove_node(address);
+        let mut removed = 1;
+        for counterparty in counterparties {
+            let Some(idx) = self.node_index(&counterparty) else {
+                continue;
+            };
+            if counterparty != self.root_address
+                && self.graph.neighbors_undirected(idx).next().is_none()
+            {
+                self.remove_node(counterparty);
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // Hops from the session root to `address`, or 0 if it isn't connected to the root
+    fn depth_of(&self, address: &Address) -> usize {
+        self.graph
+            .node(address)
+            .and_then(|node| node.depth)
+            .unwrap_or_default()
+    }
+
+    fn remove_node(&mut self, address: Address) {
+        self.graph.remove_node(&address);
+        self.arrivals.remove(&address);
+        self.expanded.remove(&address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn session() -> TraversalSession<MemorySource> {
+        let source = MemorySource::new(vec![
+            transfer(1, 2, 10, 0, 5),
+            transfer(1, 3, 10, 1, 5),
+            transfer(2, 4, 11, 0, 5),
+            transfer(3, 5, 11, 1, 5),
+        ]);
+        TraversalSession::new(source, address(1), 0, 100, vec![TOKEN])
+    }
+
+    fn path(graph: &TransferGraph, to: u8) -> Vec<(Address, Address)> {
+        graph
+            .explain_path(&address(to))
+            .unwrap()
+            .into_iter()
+            .map(|edge| {
+                let (source, target) = graph.edge_endpoints(edge).unwrap();
+                (graph[source].address, graph[target].address)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prune_keeps_moved_discovering_edges() {
+        let mut session = session();
+        session.expand(address(1), 2).unwrap();
+        assert_eq!(session.graph().edge_count(), 4);
+
+        // 2 -> 4 leaves 4 with no transfers, and 3 -> 5 is moved into a freed edge slot
+        assert_eq!(session.prune(address(2)).unwrap(), 2);
+
+        let graph = session.graph();
+        assert_eq!(graph.node_count(), 3);
+        assert!(!graph.contains(&address(4)));
+        assert_eq!(
+            path(graph, 5),
+            vec![(address(1), address(3)), (address(3), address(5))]
+        );
+    }
+
+    #[test]
+    fn pruned_addresses_stay_out_of_later_expansions() {
+        let mut session = session();
+        session.expand(address(1), 1).unwrap();
+        session.prune(address(2)).unwrap();
+        session.expand(address(1), 2).unwrap();
+
+        assert!(!session.graph().contains(&address(2)));
+        assert!(session.prune(address(1)).is_err());
+        assert_eq!(
+            path(session.graph(), 5),
+            vec![(address(1), address(3)), (address(3), address(5))]
+        );
+    }
+}
@@ -1,52 +1,171 @@
-use crate::types::TransferGraph;
-use alloy_primitives::Address;
+use crate::types::{TransferEdge, TransferGraph};
+use alloy_primitives::{
+    Address,
+    aliases::{BlockNumber, TxHash, U256},
+};
+use anyhow::{Context, Result};
 use petgraph::Directed;
 use petgraph::Graph;
 use petgraph::graph::NodeIndex;
 use petgraph::visit::EdgeRef;
+use serde::{Serialize, Serializer};
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Debug, Display};
+use std::io::Write;
+use std::str::FromStr;
 
-// TODO: feat. add support for sum_amount
-#[derive(Debug)]
+/// TokenAmounts
+///
+/// Amount statistics for one token on one summary edge, in raw units.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenAmounts {
+    pub token: Address,
+    pub no_transfers: usize,
+    /// Saturates at U256::MAX rather than overflowing.
+    #[serde(serialize_with = "decimal")]
+    pub total: U256,
+    #[serde(serialize_with = "decimal")]
+    pub min: U256,
+    #[serde(serialize_with = "decimal")]
+    pub max: U256,
+    /// Lower median for an even number of transfers.
+    #[serde(serialize_with = "decimal")]
+    pub median: U256,
+}
+
+impl TokenAmounts {
+    // amounts must be non-empty
+    fn from_amounts(token: Address, mut amounts: Vec<U256>) -> Self {
+        amounts.sort_unstable();
+        Self {
+            token,
+            no_transfers: amounts.len(),
+            total: amounts
+                .iter()
+                .fold(U256::ZERO, |total, amount| total.saturating_add(*amount)),
+            min: amounts[0],
+            max: amounts[amounts.len() - 1],
+            median: amounts[(amounts.len() - 1) / 2],
+        }
+    }
+}
+
+impl Display for TokenAmounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {:.36} (min {}, median {}, max {})",
+            self.total, self.token, self.min, self.median, self.max
+        )
+    }
+}
+
+/// SummaryEdge
+///
+/// Every transfer from one address to another, aggregated. Amounts are kept per token since
+/// raw amounts of different tokens can't be added up.
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct SummaryEdge {
     pub no_transfers: usize,
+    pub distinct_txs: usize,
+    pub first_block: BlockNumber,
+    pub last_block: BlockNumber,
+    /// Sorted by token address.
+    pub tokens: Vec<TokenAmounts>,
 }
 
 impl SummaryEdge {
     pub fn new(no_transfers: usize) -> Self {
         Self {
             no_transfers: no_transfers,
+            ..Default::default()
+        }
+    }
+
+    /// Aggregate transfers between one pair of addresses. `edges` must be non-empty.
+    pub fn from_edges(edges: &[&TransferEdge]) -> Self {
+        let mut amounts: BTreeMap<Address, Vec<U256>> = BTreeMap::new();
+        let mut txs: HashSet<TxHash> = HashSet::new();
+        for edge in edges {
+            amounts.entry(edge.token).or_default().push(edge.amount);
+            txs.insert(edge.tx_hash);
+        }
+
+        Self {
+            no_transfers: edges.len(),
+            distinct_txs: txs.len(),
+            first_block: edges
+                .iter()
+                .map(|edge| edge.block_number)
+                .min()
+                .unwrap_or(0),
+            last_block: edges
+                .iter()
+                .map(|edge| edge.block_number)
+                .max()
+                .unwrap_or(0),
+            tokens: amounts
+                .into_iter()
+                .map(|(token, amounts)| TokenAmounts::from_amounts(token, amounts))
+                .collect(),
         }
     }
+
+    /// Total raw amount of `token` on this edge.
+    pub fn amount_of(&self, token: &Address) -> U256 {
+        self.tokens
+            .iter()
+            .find(|amounts| amounts.token == *token)
+            .map_or(U256::ZERO, |amounts| amounts.total)
+    }
 }
 
 impl Display for SummaryEdge {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.no_transfers)
+        write!(
+            f,
+            "{} transfers in {} txs, blocks {}..={}",
+            self.no_transfers, self.distinct_txs, self.first_block, self.last_block
+        )?;
+        for amounts in &self.tokens {
+            write!(f, "\n    {}", amounts)?;
+        }
+        Ok(())
     }
 }
 
-// TODO: Add support for sum_amount
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct AggregatedTransfer {
     pub from: Address,
     pub to: Address,
     pub no_transfers: usize,
+    pub summary: SummaryEdge,
 }
 
 impl Display for AggregatedTransfer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        _ = write!(
+        writeln!(
             f,
-            "{:.36} -> {:.36} transferred {} times.\n",
-            self.from, self.to, self.no_transfers
-        );
-        Ok(())
+            "{:.36} -> {:.36} for {}",
+            self.from, self.to, self.summary
+        )
     }
 }
 
+/// TokenVolume
+///
+/// How much of one token moved through the graph. In any-token mode this is how you find out
+/// which tokens the trail went through.
+#[derive(Debug, Clone)]
+pub struct TokenVolume {
+    pub token: Address,
+    pub no_transfers: usize,
+    /// Sum of raw amounts, so not comparable across tokens with different decimals.
+    pub volume: U256,
+}
+
 /// TransferSummary
 ///
 /// A TransferSummary is primarily a graph that aggregates many TransferEdges between nodes.
@@ -56,32 +175,36 @@ impl Display for AggregatedTransfer {
 pub struct TransferSummary {
     pub summary_graph: petgraph::Graph<Address, SummaryEdge, Directed>,
     pub summary_table: Option<Vec<AggregatedTransfer>>,
+    /// Tokens seen in the graph, most transfers first.
+    pub tokens: Vec<TokenVolume>,
 }
 
-// TODO: Add filtering for >1 transfers
 impl TransferSummary {
     pub fn new() -> Self {
         let graph = Graph::<Address, SummaryEdge, Directed>::new();
         Self {
             summary_graph: graph,
             summary_table: None,
+            tokens: Vec::new(),
         }
     }
 
     pub fn from_transfer_graph(graph: &TransferGraph) -> Self {
-        // Accumulate/count all transfers from the transfer graph
-        let mut acc: HashMap<(Address, Address), usize> = HashMap::new();
+        // Accumulate all transfers from the transfer graph by (from, to)
+        let mut acc: HashMap<(Address, Address), Vec<&TransferEdge>> = HashMap::new();
 
         for edge in graph.edge_references() {
             let key = (edge.source(), edge.target());
-            *acc.entry((graph[key.0], graph[key.1])).or_insert(0) += 1;
+            acc.entry((graph[key.0].address, graph[key.1].address))
+                .or_default()
+                .push(edge.weight());
         }
 
         // Add the nodes and edges to the summary graph
         let mut summary_graph = Graph::<Address, SummaryEdge, Directed>::new();
         let mut node_map = HashMap::<Address, NodeIndex>::new();
 
-        for ((from, to), count) in acc {
+        for ((from, to), edges) in acc {
             let from_index = *node_map
                 .entry(from)
                 .or_insert_with(|| summary_graph.add_node(from));
@@ -89,22 +212,46 @@ impl TransferSummary {
                 .entry(to)
                 .or_insert_with(|| summary_graph.add_node(to));
 
-            summary_graph.add_edge(
-                from_index,
-                to_index,
-                SummaryEdge {
-                    no_transfers: count,
-                },
-            );
+            summary_graph.add_edge(from_index, to_index, SummaryEdge::from_edges(&edges));
         }
 
         TransferSummary {
             summary_graph: summary_graph,
             summary_table: None,
+            tokens: Self::token_volumes(graph),
+        }
+    }
+
+    fn token_volumes(graph: &TransferGraph) -> Vec<TokenVolume> {
+        let mut volumes: HashMap<Address, TokenVolume> = HashMap::new();
+        for edge in graph.edge_weights() {
+            let volume = volumes.entry(edge.token).or_insert(TokenVolume {
+                token: edge.token,
+                no_transfers: 0,
+                volume: U256::ZERO,
+            });
+            volume.no_transfers += 1;
+            volume.volume = volume.volume.saturating_add(edge.amount);
         }
+
+        let mut volumes: Vec<TokenVolume> = volumes.into_values().collect();
+        volumes.sort_by(|a, b| {
+            b.no_transfers
+                .cmp(&a.no_transfers)
+                .then_with(|| a.token.cmp(&b.token))
+        });
+        volumes
     }
 
     pub fn with_summary_table(self) -> Self {
+        Self {
+            summary_table: Some(self.table_rows()),
+            ..self
+        }
+    }
+
+    // One row per summary edge, in graph order
+    fn table_rows(&self) -> Vec<AggregatedTransfer> {
         let mut aggregated_transfers = Vec::new();
 
         self.summary_graph
@@ -118,18 +265,295 @@ impl TransferSummary {
                     from,
                     to,
                     no_transfers,
+                    summary: edge.weight().clone(),
                 });
             });
 
-        Self {
-            summary_graph: self.summary_graph,
-            summary_table: Some(aggregated_transfers),
-        }
+        aggregated_transfers
     }
 
     pub fn has_summary_table(&self) -> bool {
         self.summary_table.is_some()
     }
+
+    /// Rows of the summary table that pass `query`, sorted and truncated as it asks. Works
+    /// whether or not the table has been built.
+    pub fn query(&self, query: &SummaryQuery) -> Vec<AggregatedTransfer> {
+        let mut rows = match &self.summary_table {
+            Some(table) => table.clone(),
+            None => self.table_rows(),
+        };
+
+        rows.retain(|row| query.matches(row));
+        rows.sort_by(|a, b| query.sort.compare(a, b, query.token.as_ref()));
+        if let Some(top) = query.top {
+            rows.truncate(top);
+        }
+        rows
+    }
+
+    /// Write the rows matching `query` to `out` as CSV, JSON or Markdown.
+    pub fn export(
+        &self,
+        query: &SummaryQuery,
+        format: SummaryFormat,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        let rows = self.query(query);
+        write_export(&rows, &EXPORT_COLUMNS, export_lines(&rows), format, out)
+    }
+}
+
+/// SummarySort
+///
+/// Order of summary table rows. Amount sorts use the query's token if it has one, and the
+/// largest per-token total otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SummarySort {
+    /// By sender, then most transfers first, as `Display` prints it.
+    #[default]
+    Address,
+    Transfers,
+    Amount,
+    FirstBlock,
+    LastBlock,
+}
+
+impl SummarySort {
+    fn compare(
+        &self,
+        a: &AggregatedTransfer,
+        b: &AggregatedTransfer,
+        token: Option<&Address>,
+    ) -> Ordering {
+        let by_address = a.from.cmp(&b.from).then_with(|| a.to.cmp(&b.to));
+        match self {
+            Self::Address => a
+                .from
+                .cmp(&b.from)
+                .then_with(|| b.no_transfers.cmp(&a.no_transfers))
+                .then_with(|| a.to.cmp(&b.to)),
+            Self::Transfers => b.no_transfers.cmp(&a.no_transfers).then(by_address),
+            Self::Amount => row_amount(b, token)
+                .cmp(&row_amount(a, token))
+                .then(by_address),
+            Self::FirstBlock => a
+                .summary
+                .first_block
+                .cmp(&b.summary.first_block)
+                .then(by_address),
+            Self::LastBlock => b
+                .summary
+                .last_block
+                .cmp(&a.summary.last_block)
+                .then(by_address),
+        }
+    }
+}
+
+impl FromStr for SummarySort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "address" => Ok(Self::Address),
+            "transfers" | "count" => Ok(Self::Transfers),
+            "amount" => Ok(Self::Amount),
+            "first-block" | "first_block" => Ok(Self::FirstBlock),
+            "last-block" | "last_block" => Ok(Self::LastBlock),
+            _ => Err(format!(
+                "unknown sort {}, expected address, transfers, amount, first-block or last-block",
+                s
+            )),
+        }
+    }
+}
+
+// Total of `token` on a row, or its largest per-token total without one
+fn row_amount(row: &AggregatedTransfer, token: Option<&Address>) -> U256 {
+    match token {
+        Some(token) => row.summary.amount_of(token),
+        None => row
+            .summary
+            .tokens
+            .iter()
+            .map(|amounts| amounts.total)
+            .max()
+            .unwrap_or(U256::ZERO),
+    }
+}
+
+/// SummaryQuery
+///
+/// Filters, order and limit for `TransferSummary::query`. The default keeps every row in
+/// `SummarySort::Address` order.
+#[derive(Debug, Clone, Default)]
+pub struct SummaryQuery {
+    pub min_transfers: usize,
+    /// Minimum total raw amount, of `token` if set and of any one token otherwise.
+    pub min_amount: Option<U256>,
+    /// Only rows that moved this token.
+    pub token: Option<Address>,
+    pub top: Option<usize>,
+    pub sort: SummarySort,
+}
+
+impl SummaryQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_min_transfers(self, min_transfers: usize) -> Self {
+        Self {
+            min_transfers,
+            ..self
+        }
+    }
+
+    pub fn with_min_amount(self, min_amount: U256) -> Self {
+        Self {
+            min_amount: Some(min_amount),
+            ..self
+        }
+    }
+
+    pub fn with_token(self, token: Address) -> Self {
+        Self {
+            token: Some(token),
+            ..self
+        }
+    }
+
+    pub fn with_top(self, top: usize) -> Self {
+        Self {
+            top: Some(top),
+            ..self
+        }
+    }
+
+    pub fn with_sort(self, sort: SummarySort) -> Self {
+        Self { sort, ..self }
+    }
+
+    fn matches(&self, row: &AggregatedTransfer) -> bool {
+        if row.no_transfers < self.min_transfers {
+            return false;
+        }
+        if let Some(token) = &self.token
+            && !row
+                .summary
+                .tokens
+                .iter()
+                .any(|amounts| amounts.token == *token)
+        {
+            return false;
+        }
+        self.min_amount
+            .is_none_or(|min_amount| row_amount(row, self.token.as_ref()) >= min_amount)
+    }
+}
+
+/// SummaryFormat
+///
+/// Export formats for the summary table, also used by the degree stats writers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryFormat {
+    /// One line per (from, to, token), for spreadsheets.
+    Csv,
+    /// The rows as a JSON array.
+    Json,
+    /// A Markdown table, one line per (from, to, token), for tickets and reports.
+    Markdown,
+}
+
+impl FromStr for SummaryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "markdown" | "md" => Ok(Self::Markdown),
+            _ => Err(format!(
+                "unknown summary format {}, expected csv, json or markdown",
+                s
+            )),
+        }
+    }
+}
+
+const EXPORT_COLUMNS: [&str; 11] = [
+    "from",
+    "to",
+    "token",
+    "transfers",
+    "total",
+    "min",
+    "median",
+    "max",
+    "txs",
+    "first_block",
+    "last_block",
+];
+
+// One export line per (row, token); addresses and hashes never need CSV quoting
+fn export_lines(rows: &[AggregatedTransfer]) -> Vec<Vec<String>> {
+    let mut lines = Vec::new();
+    for row in rows {
+        for amounts in &row.summary.tokens {
+            lines.push(vec![
+                row.from.to_string(),
+                row.to.to_string(),
+                amounts.token.to_string(),
+                amounts.no_transfers.to_string(),
+                amounts.total.to_string(),
+                amounts.min.to_string(),
+                amounts.median.to_string(),
+                amounts.max.to_string(),
+                row.summary.distinct_txs.to_string(),
+                row.summary.first_block.to_string(),
+                row.summary.last_block.to_string(),
+            ]);
+        }
+    }
+    lines
+}
+
+// Amounts go into JSON exports as decimal strings, like in CSV and Markdown, rather than the
+// hex strings U256 serializes to by default
+pub(crate) fn decimal<S: Serializer>(amount: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(amount)
+}
+
+/// Write `rows` to `out` in `format`. JSON serializes the rows themselves, while CSV and
+/// Markdown write `columns` as a header and then `lines`, which must not need quoting.
+pub(crate) fn write_export<T: Serialize>(
+    rows: &[T],
+    columns: &[&str],
+    lines: Vec<Vec<String>>,
+    format: SummaryFormat,
+    out: &mut impl Write,
+) -> Result<()> {
+    match format {
+        SummaryFormat::Csv => {
+            writeln!(out, "{}", columns.join(","))?;
+            for line in lines {
+                writeln!(out, "{}", line.join(","))?;
+            }
+        }
+        SummaryFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, rows).context("Failed to write JSON")?;
+            writeln!(out)?;
+        }
+        SummaryFormat::Markdown => {
+            writeln!(out, "| {} |", columns.join(" | "))?;
+            writeln!(out, "|{}", "---|".repeat(columns.len()))?;
+            for line in lines {
+                writeln!(out, "| {} |", line.join(" | "))?;
+            }
+        }
+    }
+    Ok(())
 }
 
 impl Display for TransferSummary {
@@ -144,25 +568,162 @@ impl Display for TransferSummary {
             });
 
             for transfer in sorted_table {
-                writeln!(
-                    f,
-                    "{:.36} -> {:.36} for {} transfers",
-                    transfer.from, transfer.to, transfer.no_transfers
-                )?;
+                write!(f, "{}", transfer)?;
             }
         } else {
             writeln!(f, "Transfer Summary:")?;
             for edge in self.summary_graph.edge_references() {
                 let from = self.summary_graph[edge.source()];
                 let to = self.summary_graph[edge.target()];
-                let no_transfers = edge.weight().no_transfers;
+                writeln!(f, "{:.36} -> {:.36} for {}", from, to, edge.weight())?;
+            }
+        }
+        if !self.tokens.is_empty() {
+            writeln!(f, "Tokens:")?;
+            for token in &self.tokens {
                 writeln!(
                     f,
-                    "{:.36} -> {:.36} for {} transfers",
-                    from, to, no_transfers
+                    "{:.36} moved {} in {} transfers",
+                    token.token, token.volume, token.no_transfers
                 )?;
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::types::Transfer;
+
+    const OTHER: Address = Address::repeat_byte(0xdd);
+
+    fn summary() -> TransferSummary {
+        TransferSummary::from_transfer_graph(&graph_of(&[
+            transfer(1, 2, 1, 0, 10),
+            transfer(1, 2, 2, 0, 20),
+            transfer(1, 2, 3, 0, 30),
+            transfer(1, 3, 4, 0, 1_000_000),
+            Transfer {
+                token: OTHER,
+                ..transfer(2, 3, 5, 0, 7)
+            },
+        ]))
+    }
+
+    fn pairs(rows: &[AggregatedTransfer]) -> Vec<(Address, Address)> {
+        rows.iter().map(|row| (row.from, row.to)).collect()
+    }
+
+    fn export(format: SummaryFormat) -> String {
+        let mut out = Vec::new();
+        summary()
+            .export(&SummaryQuery::new(), format, &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_export_has_a_line_per_pair_and_token() {
+        let csv = export(SummaryFormat::Csv);
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(lines[0], EXPORT_COLUMNS.join(","));
+        assert_eq!(
+            lines[1],
+            format!(
+                "{},{},{},3,60,10,20,30,3,1,3",
+                address(1),
+                address(2),
+                TOKEN
+            )
+        );
+        assert_eq!(
+            lines[3],
+            format!("{},{},{},1,7,7,7,7,1,5,5", address(2), address(3), OTHER)
+        );
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn json_export_writes_amounts_as_decimal_strings() {
+        let json: serde_json::Value = serde_json::from_str(&export(SummaryFormat::Json)).unwrap();
+
+        let rows = json.as_array().unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0]["no_transfers"], 3);
+        assert_eq!(rows[0]["summary"]["tokens"][0]["median"], "20");
+        assert_eq!(rows[1]["summary"]["tokens"][0]["total"], "1000000");
+    }
+
+    #[test]
+    fn markdown_export_is_a_table() {
+        let markdown = export(SummaryFormat::Markdown);
+        let lines: Vec<&str> = markdown.lines().collect();
+
+        assert_eq!(lines[0], format!("| {} |", EXPORT_COLUMNS.join(" | ")));
+        assert_eq!(lines[1], "|---|---|---|---|---|---|---|---|---|---|---|");
+        assert!(lines[2].starts_with(&format!("| {} | {} |", address(1), address(2))));
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn query_filters_rows() {
+        let summary = summary();
+        let query = |query: SummaryQuery| pairs(&summary.query(&query));
+
+        assert_eq!(
+            query(SummaryQuery::new().with_min_transfers(2)),
+            vec![(address(1), address(2))]
+        );
+        assert_eq!(
+            query(SummaryQuery::new().with_token(OTHER)),
+            vec![(address(2), address(3))]
+        );
+        assert_eq!(
+            query(SummaryQuery::new().with_min_amount(U256::from(50))),
+            vec![(address(1), address(2)), (address(1), address(3))]
+        );
+        // With a token, the amount is that token's, so 2 -> 3 moved none of TOKEN
+        assert_eq!(
+            query(
+                SummaryQuery::new()
+                    .with_token(TOKEN)
+                    .with_min_amount(U256::from(100))
+            ),
+            vec![(address(1), address(3))]
+        );
+    }
+
+    #[test]
+    fn query_sorts_and_truncates() {
+        let summary = summary();
+        let sorted = |sort| pairs(&summary.query(&SummaryQuery::new().with_sort(sort)));
+
+        assert_eq!(
+            sorted(SummarySort::Amount),
+            vec![
+                (address(1), address(3)),
+                (address(1), address(2)),
+                (address(2), address(3))
+            ]
+        );
+        assert_eq!(
+            sorted(SummarySort::LastBlock),
+            vec![
+                (address(2), address(3)),
+                (address(1), address(3)),
+                (address(1), address(2))
+            ]
+        );
+        assert_eq!(sorted(SummarySort::FirstBlock)[0], (address(1), address(2)));
+        let top = summary.query(
+            &SummaryQuery::new()
+                .with_sort(SummarySort::Transfers)
+                .with_top(1),
+        );
+        assert_eq!(pairs(&top), vec![(address(1), address(2))]);
+    }
+}
@@ -0,0 +1,375 @@
+use crate::types::TransferGraph;
+use alloy_primitives::{
+    Address,
+    aliases::{U256, U512},
+};
+use petgraph::graph::EdgeIndex;
+use petgraph::visit::EdgeRef;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// TaintModel
+///
+/// How tainted funds mix with clean funds when an address sends a transfer.
+/// - `Poison`: once an address holds any taint, everything it sends is fully tainted.
+/// - `Haircut`: every transfer carries taint in proportion to the sender's tainted balance.
+/// - `Fifo`: funds leave in the order they arrived, so taint moves with the oldest lots first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaintModel {
+    Poison,
+    Haircut,
+    Fifo,
+}
+
+impl FromStr for TaintModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "poison" => Ok(Self::Poison),
+            "haircut" | "proportional" => Ok(Self::Haircut),
+            "fifo" => Ok(Self::Fifo),
+            _ => Err(format!(
+                "unknown taint model {}, expected poison, haircut or fifo",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for TaintModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Poison => write!(f, "poison"),
+            Self::Haircut => write!(f, "haircut"),
+            Self::Fifo => write!(f, "fifo"),
+        }
+    }
+}
+
+/// TaintSeed
+///
+/// An address whose holdings of `token` are tainted at the start of the graph, e.g. the
+/// exploiter holding 1,200 stolen WETH.
+///
+/// With no `amount`, everything the seed sends of that token is treated as tainted.
+#[derive(Debug, Clone)]
+pub struct TaintSeed {
+    pub address: Address,
+    pub token: Address,
+    pub amount: Option<U256>,
+}
+
+impl TaintSeed {
+    pub fn new(address: Address, token: Address) -> Self {
+        Self {
+            address,
+            token,
+            amount: None,
+        }
+    }
+
+    pub fn with_amount(self, amount: U256) -> Self {
+        Self {
+            amount: Some(amount),
+            ..self
+        }
+    }
+}
+
+/// NodeTaint
+///
+/// Tainted amounts of a single token seen at an address.
+#[derive(Debug, Clone, Default)]
+pub struct NodeTaint {
+    pub seeded: U256,
+    pub received: U256,
+    pub sent: U256,
+}
+
+impl NodeTaint {
+    /// Tainted amount still held at the end of the graph.
+    pub fn held(&self) -> U256 {
+        self.seeded
+            .saturating_add(self.received)
+            .saturating_sub(self.sent)
+    }
+}
+
+/// TaintReport
+///
+/// Output of `propagate_taint`: tainted amounts per (address, token) and per edge.
+pub struct TaintReport {
+    pub model: TaintModel,
+    pub nodes: HashMap<(Address, Address), NodeTaint>,
+    pub edges: HashMap<EdgeIndex, U256>,
+}
+
+impl TaintReport {
+    pub fn node(&self, address: &Address, token: &Address) -> Option<&NodeTaint> {
+        self.nodes.get(&(*address, *token))
+    }
+
+    /// Tainted amount carried by an edge; zero for clean edges.
+    pub fn edge(&self, edge: EdgeIndex) -> U256 {
+        self.edges.get(&edge).copied().unwrap_or(U256::ZERO)
+    }
+}
+
+impl Display for TaintReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut rows: Vec<(&(Address, Address), &NodeTaint)> = self
+            .nodes
+            .iter()
+            .filter(|(_, taint)| taint.received > U256::ZERO)
+            .collect();
+        rows.sort_by(|a, b| b.1.received.cmp(&a.1.received).then(a.0.cmp(b.0)));
+
+        writeln!(f, "Taint ({} model):", self.model)?;
+        for ((address, token), taint) in rows {
+            writeln!(
+                f,
+                "{:.36} received {} tainted of token {:.36} ({} still held)",
+                address,
+                taint.received,
+                token,
+                taint.held()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Per (address, token) balance as seen by the taint engine
+#[derive(Default)]
+struct Holding {
+    clean: U256,
+    tainted: U256,
+    // FIFO lots, oldest first, as (amount, is_tainted)
+    lots: VecDeque<(U256, bool)>,
+    // a seed with no amount taints everything it sends
+    unbounded: bool,
+}
+
+impl Holding {
+    // Remove `amount` from the holding and return how much of it is tainted
+    fn withdraw(&mut self, model: TaintModel, amount: U256) -> U256 {
+        if self.unbounded {
+            return amount;
+        }
+        if amount == U256::ZERO {
+            return U256::ZERO;
+        }
+
+        // Anything sent beyond what we saw arrive is funds from before the graph; count it clean
+        let total = self.clean.saturating_add(self.tainted);
+        if total < amount {
+            let shortfall = amount - total;
+            self.clean = self.clean.saturating_add(shortfall);
+            self.lots.push_front((shortfall, false));
+        }
+
+        let tainted = match model {
+            TaintModel::Poison => {
+                if self.tainted > U256::ZERO {
+                    amount
+                } else {
+                    U256::ZERO
+                }
+            }
+            TaintModel::Haircut => {
+                let total = self.clean.saturating_add(self.tainted);
+                // amount * tainted / total in 512 bits so it can't overflow
+                U256::from(U512::from(amount) * U512::from(self.tainted) / U512::from(total))
+            }
+            TaintModel::Fifo => {
+                let mut remaining = amount;
+                let mut tainted = U256::ZERO;
+                while remaining > U256::ZERO {
+                    let Some((lot, is_tainted)) = self.lots.pop_front() else {
+                        break;
+                    };
+                    let taken = lot.min(remaining);
+                    if is_tainted {
+                        tainted = tainted.saturating_add(taken);
+                    }
+                    if taken < lot {
+                        self.lots.push_front((lot - taken, is_tainted));
+                    }
+                    remaining -= taken;
+                }
+                tainted
+            }
+        };
+
+        match model {
+            // Poison never cleans an address, so only the clean balance goes down
+            TaintModel::Poison => self.clean = self.clean.saturating_sub(amount),
+            _ => {
+                self.tainted = self.tainted.saturating_sub(tainted);
+                self.clean = self.clean.saturating_sub(amount - tainted);
+            }
+        }
+
+        tainted
+    }
+
+    fn deposit(&mut self, amount: U256, tainted: U256) {
+        self.tainted = self.tainted.saturating_add(tainted);
+        self.clean = self.clean.saturating_add(amount - tainted);
+        // Within one transfer the tainted part is queued first
+        if tainted > U256::ZERO {
+            self.lots.push_back((tainted, true));
+        }
+        if amount > tainted {
+            self.lots.push_back((amount - tainted, false));
+        }
+    }
+}
+
+/// Propagate taint from `seeds` through the graph's transfers in time order.
+///
+/// Edges are replayed by (block, log index), each token tracked separately. Tokens without a
+/// seed are skipped. Addresses start with no balance; when one sends more than the graph shows
+/// it receiving, the difference is assumed to be clean funds it already held.
+pub fn propagate_taint(
+    graph: &TransferGraph,
+    seeds: &[TaintSeed],
+    model: TaintModel,
+) -> TaintReport {
+    let mut holdings: HashMap<(Address, Address), Holding> = HashMap::new();
+    let mut nodes: HashMap<(Address, Address), NodeTaint> = HashMap::new();
+    let mut edges: HashMap<EdgeIndex, U256> = HashMap::new();
+
+    for seed in seeds {
+        let holding = holdings.entry((seed.address, seed.token)).or_default();
+        let node = nodes.entry((seed.address, seed.token)).or_default();
+        match seed.amount {
+            Some(amount) => {
+                holding.deposit(amount, amount);
+                node.seeded = node.seeded.saturating_add(amount);
+            }
+            None => holding.unbounded = true,
+        }
+    }
+
+    let mut ordered: Vec<_> = graph
+        .edge_references()
+        .filter(|edge| seeds.iter().any(|seed| seed.token == edge.weight().token))
+        .collect();
+    ordered.sort_by_key(|edge| (edge.weight().position(), edge.id()));
+
+    for edge in ordered {
+        let weight = edge.weight();
+        let from = graph[edge.source()].address;
+        let to = graph[edge.target()].address;
+
+        let tainted = holdings
+            .entry((from, weight.token))
+            .or_default()
+            .withdraw(model, weight.amount);
+        holdings
+            .entry((to, weight.token))
+            .or_default()
+            .deposit(weight.amount, tainted);
+
+        if tainted > U256::ZERO {
+            edges.insert(edge.id(), tainted);
+            let sender = nodes.entry((from, weight.token)).or_default();
+            sender.sent = sender.sent.saturating_add(tainted);
+            let recipient = nodes.entry((to, weight.token)).or_default();
+            recipient.received = recipient.received.saturating_add(tainted);
+        }
+    }
+
+    TaintReport {
+        model,
+        nodes,
+        edges,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    // 1 is seeded with 100. 2 mixes it with 40 clean from 3, overspends its balance by 30
+    // sending to 5, and gets funds back from 4 around the 2 -> 4 -> 2 cycle.
+    fn mixing_graph() -> TransferGraph {
+        graph_of(&[
+            transfer(1, 2, 1, 0, 60),
+            transfer(3, 2, 2, 0, 40),
+            transfer(2, 4, 3, 0, 50),
+            transfer(2, 5, 4, 0, 80),
+            transfer(4, 2, 5, 0, 50),
+            transfer(2, 6, 6, 0, 50),
+        ])
+    }
+
+    fn edge_taint(model: TaintModel) -> (TaintReport, Vec<U256>) {
+        let graph = mixing_graph();
+        let seed = TaintSeed::new(address(1), TOKEN).with_amount(U256::from(100));
+        let report = propagate_taint(&graph, &[seed], model);
+        let edges = graph.edge_indices().map(|edge| report.edge(edge)).collect();
+        (report, edges)
+    }
+
+    fn amounts(amounts: &[u64]) -> Vec<U256> {
+        amounts.iter().map(|amount| U256::from(*amount)).collect()
+    }
+
+    #[test]
+    fn poison_taints_everything_after_contact() {
+        let (report, edges) = edge_taint(TaintModel::Poison);
+
+        assert_eq!(edges, amounts(&[60, 0, 50, 80, 50, 50]));
+        let node = report.node(&address(2), &TOKEN).unwrap();
+        assert_eq!(node.received, U256::from(110));
+        assert_eq!(node.sent, U256::from(180));
+        assert_eq!(node.held(), U256::ZERO);
+    }
+
+    #[test]
+    fn haircut_splits_taint_by_balance() {
+        let (report, edges) = edge_taint(TaintModel::Haircut);
+
+        // 2 -> 5 sends the 30 tainted left plus 20 clean and the 30 shortfall
+        assert_eq!(edges, amounts(&[60, 0, 30, 30, 30, 30]));
+        let node = report.node(&address(2), &TOKEN).unwrap();
+        assert_eq!(node.received, U256::from(90));
+        assert_eq!(node.sent, U256::from(90));
+        assert_eq!(
+            report.node(&address(1), &TOKEN).unwrap().held(),
+            U256::from(40)
+        );
+    }
+
+    #[test]
+    fn fifo_sends_the_oldest_lots_first() {
+        let (report, edges) = edge_taint(TaintModel::Fifo);
+
+        // The shortfall in 2 -> 5 is taken before the 10 tainted and 40 clean left
+        assert_eq!(edges, amounts(&[60, 0, 50, 10, 50, 50]));
+        assert!(report.node(&address(3), &TOKEN).is_none());
+        assert_eq!(
+            report.node(&address(5), &TOKEN).unwrap().received,
+            U256::from(10)
+        );
+    }
+
+    #[test]
+    fn seed_without_amount_taints_all_it_sends() {
+        let graph = mixing_graph();
+        let report = propagate_taint(
+            &graph,
+            &[TaintSeed::new(address(3), TOKEN)],
+            TaintModel::Haircut,
+        );
+
+        // Only 3's 40 is tainted; 2 then sends 50 of its 100
+        let edges: Vec<U256> = graph.edge_indices().map(|edge| report.edge(edge)).collect();
+        assert_eq!(edges, amounts(&[0, 40, 20, 20, 20, 20]));
+    }
+}
@@ -0,0 +1,185 @@
+// In-memory data source and transfer helpers shared by the unit tests
+
+use crate::{
+    data_sources::*,
+    progress::{CancellationToken, TraversalObserver},
+    types::*,
+};
+use alloy_primitives::{Address, B256, BlockNumber, U256};
+use petgraph::visit::EdgeRef;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// The token every `transfer` moves.
+pub(crate) const TOKEN: Address = Address::repeat_byte(0xee);
+
+/// A distinct address per `n`.
+pub(crate) fn address(n: u8) -> Address {
+    Address::with_last_byte(n)
+}
+
+/// A transfer of `amount` of `TOKEN` from address `from` to address `to`, in its own
+/// transaction at (`block`, `log_index`).
+pub(crate) fn transfer(
+    from: u8,
+    to: u8,
+    block: BlockNumber,
+    log_index: u64,
+    amount: u64,
+) -> Transfer {
+    let mut tx_hash = [0u8; 32];
+    tx_hash[..8].copy_from_slice(&block.to_be_bytes());
+    tx_hash[8..16].copy_from_slice(&log_index.to_be_bytes());
+    Transfer::new(
+        B256::from(tx_hash),
+        block,
+        log_index,
+        address(from),
+        address(to),
+        TOKEN,
+        U256::from(amount),
+    )
+}
+
+/// A graph with one edge per transfer, in order, and no traversal metadata.
+pub(crate) fn graph_of(transfers: &[Transfer]) -> TransferGraph {
+    let mut graph = TransferGraph::new();
+    for transfer in transfers {
+        let from = graph.add_node(TransferNode::new(transfer.from_address));
+        let to = graph.add_node(TransferNode::new(transfer.to_address));
+        graph.add_edge(
+            from,
+            to,
+            TransferEdge {
+                amount: transfer.amount,
+                tx_hash: transfer.tx_hash,
+                block_number: transfer.block_number,
+                log_index: transfer.log_index,
+                token: transfer.token,
+            },
+        );
+    }
+    graph
+}
+
+/// Every node and edge of `graph` in index order, to compare two builds.
+pub(crate) fn snapshot(graph: &TransferGraph) -> (Vec<String>, Vec<String>) {
+    let nodes = graph
+        .node_weights()
+        .map(|node| format!("{:?}", node))
+        .collect();
+    let edges = graph
+        .edge_references()
+        .map(|edge| {
+            format!(
+                "{} -> {} {:?}",
+                edge.source().index(),
+                edge.target().index(),
+                edge.weight()
+            )
+        })
+        .collect();
+    (nodes, edges)
+}
+
+/// A path in the temp directory unique to this process and `name`, removed if it exists.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("txngraphs-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// CancelAfterDepth
+///
+/// Cancels its token once the traversal finishes `depth`, to stop a build between tiers.
+pub(crate) struct CancelAfterDepth {
+    pub depth: usize,
+    pub token: CancellationToken,
+}
+
+impl TraversalObserver for CancelAfterDepth {
+    fn on_depth_completed(&self, depth: usize, _nodes: usize, _edges: usize) {
+        if depth == self.depth {
+            self.token.cancel();
+        }
+    }
+}
+
+/// MemorySource
+///
+/// A data source over a fixed list of transfers. Returns an address's outgoing transfers in
+/// time order, like the reth source.
+#[derive(Default)]
+pub(crate) struct MemorySource {
+    pub transfers: Vec<Transfer>,
+    pub contracts: HashSet<Address>,
+}
+
+impl MemorySource {
+    pub fn new(mut transfers: Vec<Transfer>) -> Self {
+        transfers.sort_by_key(|transfer| transfer.position());
+        Self {
+            transfers,
+            ..Self::default()
+        }
+    }
+
+    fn matching(
+        &self,
+        token_addresses: &[Address],
+        block_start: BlockNumber,
+        block_end: BlockNumber,
+        keep: impl Fn(&Transfer) -> bool,
+    ) -> Vec<Transfer> {
+        self.transfers
+            .iter()
+            .filter(|transfer| {
+                (block_start..=block_end).contains(&transfer.block_number)
+                    && token_matches(token_addresses, &transfer.token)
+                    && keep(transfer)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+impl TransferDataSource for MemorySource {
+    fn get_transfers(
+        &self,
+        address: &Address,
+        token_addresses: &[Address],
+        block_start: &BlockNumber,
+        block_end: &BlockNumber,
+    ) -> anyhow::Result<Vec<Transfer>> {
+        Ok(
+            self.matching(token_addresses, *block_start, *block_end, |transfer| {
+                transfer.from_address == *address
+            }),
+        )
+    }
+
+    fn is_contract(&self, address: &Address) -> anyhow::Result<bool> {
+        Ok(self.contracts.contains(address))
+    }
+
+    fn get_token_transfers(
+        &self,
+        token_addresses: &[Address],
+        block_start: &BlockNumber,
+        block_end: &BlockNumber,
+    ) -> anyhow::Result<Vec<Transfer>> {
+        Ok(self.matching(token_addresses, *block_start, *block_end, |_| true))
+    }
+
+    fn get_transaction_transfers(
+        &self,
+        tx_hash: &B256,
+        token_addresses: &[Address],
+    ) -> anyhow::Result<Vec<Transfer>> {
+        Ok(
+            self.matching(token_addresses, 0, BlockNumber::MAX, |transfer| {
+                transfer.tx_hash == *tx_hash
+            }),
+        )
+    }
+}
@@ -1,8 +1,243 @@
-use crate::{data_sources::*, types::*};
-use alloy_primitives::{Address, BlockNumber};
-use anyhow::Result;
+use crate::{
+    checkpoint::{Checkpoint, TraversalParams},
+    data_sources::*,
+    policy::ExpansionPolicy,
+    progress::{CancellationToken, Cancelled, TraversalObserver},
+    types::*,
+};
+use alloy_primitives::{Address, B256, BlockNumber, U256};
+use anyhow::{Context, Result, bail};
 use petgraph::graph::NodeIndex;
-use std::collections::{HashMap, HashSet, VecDeque};
+use petgraph::visit::EdgeRef;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::info;
+
+/// TemporalWindow
+///
+/// Makes the traversal time-respecting: a node is only expanded for transfers at or after the
+/// (block, log index) at which funds first reached it, instead of the whole block range.
+///
+/// `max_hop_blocks` optionally caps how far past that receipt we keep following a node, e.g.
+/// 604_800 blocks for "7 days after receipt" on a chain with 1s blocks like Unichain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TemporalWindow {
+    pub max_hop_blocks: Option<u64>,
+}
+
+impl TemporalWindow {
+    pub fn new() -> Self {
+        Self {
+            max_hop_blocks: None,
+        }
+    }
+
+    pub fn with_max_hop_blocks(self, max_hop_blocks: u64) -> Self {
+        Self {
+            max_hop_blocks: Some(max_hop_blocks),
+        }
+    }
+}
+
+/// HubCutoff
+///
+/// How the traversal spots super-nodes (DEX routers, CEX hot wallets) that would blow the graph
+/// up. Hubs stay in the graph as terminal nodes and are listed in `TraversalResult::truncated`.
+/// The root is always expanded.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum HubCutoff {
+    /// Treat a node as a hub once the data source finds more than this many of its
+    /// transfers, without fetching the rest.
+    TransferCount(usize),
+    /// Learn the cutoff from the nodes expanded so far: once `min_samples` have been seen, a
+    /// node with more than `multiplier` times their median transfer count (and at least
+    /// `floor`) is a hub.
+    Learned {
+        multiplier: f64,
+        min_samples: usize,
+        floor: usize,
+    },
+}
+
+/// TruncationReason
+///
+/// Why a node was kept in the graph without being expanded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TruncationReason {
+    /// The data source found more than the configured `threshold` transfers and stopped
+    /// looking.
+    TransferCount { threshold: usize },
+    /// The node had `count` transfers, above the `cutoff` learned from earlier nodes.
+    LearnedCutoff { count: usize, cutoff: usize },
+}
+
+impl Display for TruncationReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TransferCount { threshold } => {
+                write!(f, "over the threshold of {} transfers", threshold)
+            }
+            Self::LearnedCutoff { count, cutoff } => {
+                write!(f, "{} transfers > learned cutoff {}", count, cutoff)
+            }
+        }
+    }
+}
+
+/// TruncatedNode
+///
+/// A node that was discovered but deliberately not expanded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TruncatedNode {
+    pub address: Address,
+    pub depth: usize,
+    pub reason: TruncationReason,
+}
+
+/// TraversalStatus
+///
+/// Whether a traversal ran to completion or stopped early with a partial graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraversalStatus {
+    Complete,
+    Cancelled,
+    TimedOut,
+    BudgetExhausted,
+}
+
+impl Display for TraversalStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Complete => write!(f, "complete"),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::TimedOut => write!(f, "timed out"),
+            Self::BudgetExhausted => write!(f, "budget exhausted"),
+        }
+    }
+}
+
+/// TraversalResult
+///
+/// The graph built by `build_transfer_graph_with_options`, plus what was left out of it.
+/// If `status` isn't `Complete`, the graph holds whatever was merged before the traversal
+/// stopped.
+pub struct TraversalResult {
+    pub status: TraversalStatus,
+    pub graph: TransferGraph,
+    pub truncated: Vec<TruncatedNode>,
+    /// Discovered nodes left unexpanded when a budget ran out, best first.
+    pub frontier: Vec<FrontierNode>,
+}
+
+impl TraversalResult {
+    /// Whether `address` was flagged as a hub and left unexpanded.
+    pub fn is_hub(&self, address: &Address) -> bool {
+        self.truncated.iter().any(|node| node.address == *address)
+    }
+}
+
+/// TraversalOptions
+///
+/// Optional behaviour for `build_transfer_graph_with_options`. The default is the plain BFS
+/// over the full block range that `build_transfer_graph` runs.
+#[derive(Clone, Default)]
+pub struct TraversalOptions {
+    pub temporal: Option<TemporalWindow>,
+    pub policy: Option<Arc<dyn ExpansionPolicy>>,
+    pub hub_cutoff: Option<HubCutoff>,
+    /// Fetch each BFS tier's addresses concurrently. The graph is the same either way.
+    pub parallel: bool,
+    pub observer: Option<Arc<dyn TraversalObserver>>,
+    /// Checked before every query; hand a clone to the data source to stop mid-scan too.
+    pub cancellation: Option<CancellationToken>,
+    /// Save a `Checkpoint` here after every completed BFS tier. Ignored by the best-first and
+    /// token graph builders.
+    pub checkpoint_path: Option<PathBuf>,
+    /// Pick up from the checkpoint at `checkpoint_path` if one exists.
+    pub resume: bool,
+}
+
+impl TraversalOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_temporal(self, window: TemporalWindow) -> Self {
+        Self {
+            temporal: Some(window),
+            ..self
+        }
+    }
+
+    pub fn with_policy(self, policy: impl ExpansionPolicy + 'static) -> Self {
+        Self {
+            policy: Some(Arc::new(policy)),
+            ..self
+        }
+    }
+
+    pub fn with_hub_cutoff(self, hub_cutoff: HubCutoff) -> Self {
+        Self {
+            hub_cutoff: Some(hub_cutoff),
+            ..self
+        }
+    }
+
+    pub fn with_parallel(self, parallel: bool) -> Self {
+        Self { parallel, ..self }
+    }
+
+    pub fn with_observer(self, observer: Arc<dyn TraversalObserver>) -> Self {
+        Self {
+            observer: Some(observer),
+            ..self
+        }
+    }
+
+    pub fn with_cancellation(self, cancellation: CancellationToken) -> Self {
+        Self {
+            cancellation: Some(cancellation),
+            ..self
+        }
+    }
+
+    /// Checkpoint to `path` after every BFS tier, resuming from it if it already exists.
+    pub fn with_checkpoint(self, path: impl Into<PathBuf>, resume: bool) -> Self {
+        Self {
+            checkpoint_path: Some(path.into()),
+            resume,
+            ..self
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(|token| token.is_cancelled())
+    }
+
+    // Status to stop with when the token fired
+    fn stop_status(&self) -> TraversalStatus {
+        match &self.cancellation {
+            Some(token) if token.is_timed_out() => TraversalStatus::TimedOut,
+            _ => TraversalStatus::Cancelled,
+        }
+    }
+
+    // Turn a cancellation error from a query into a stop status; other errors pass through
+    pub(crate) fn stop_on_cancel(&self, err: anyhow::Error) -> Result<TraversalStatus> {
+        if err.is::<Cancelled>() || self.is_cancelled() {
+            Ok(self.stop_status())
+        } else {
+            Err(err)
+        }
+    }
+}
 
 pub fn build_transfer_graph<D: TransferDataSource>(
     data_source: &D,
@@ -12,60 +247,1114 @@ pub fn build_transfer_graph<D: TransferDataSource>(
     token_addresses: &[Address],
     max_depth: usize,
 ) -> Result<TransferGraph> {
+    build_transfer_graph_with_options(
+        data_source,
+        root_address,
+        block_start,
+        block_end,
+        token_addresses,
+        max_depth,
+        &TraversalOptions::default(),
+    )
+    .map(|result| result.graph)
+}
+
+pub fn build_transfer_graph_with_options<D: TransferDataSource>(
+    data_source: &D,
+    root_address: Address,
+    block_start: BlockNumber,
+    block_end: BlockNumber,
+    token_addresses: &[Address],
+    max_depth: usize,
+    options: &TraversalOptions,
+) -> Result<TraversalResult> {
+    let mut builder = GraphBuilder::new(
+        data_source,
+        root_address,
+        block_start,
+        block_end,
+        token_addresses,
+        max_depth,
+        options,
+    );
+    let status = run_tiers(&mut builder, vec![root_address], 0)?;
+    Ok(builder.finish(status, Vec::new()))
+}
+
+/// Build a transfer graph starting from a transaction instead of an address, e.g. an exploit.
+///
+/// Every transfer of `token_addresses` in the transaction becomes a depth-0 edge, then each
+/// recipient is traced forward from the transaction's block to `block_end`, as
+/// `build_transfer_graph_with_options` would trace the root's recipients. Known protocol
+/// contracts in the transaction can be kept as leaves with a `TerminalAddresses` or
+/// `NoContractExpansion` policy.
+///
+/// The first sender in the transaction is used as the root, so it shows up as node 0 and in
+/// checkpoints.
+pub fn build_transfer_graph_from_tx<D: TransferDataSource>(
+    data_source: &D,
+    tx_hash: B256,
+    block_end: BlockNumber,
+    token_addresses: &[Address],
+    max_depth: usize,
+    options: &TraversalOptions,
+) -> Result<TraversalResult> {
+    let seeds = data_source
+        .get_transaction_transfers(&tx_hash, token_addresses)
+        .with_context(|| format!("Failed to get transfers for transaction {}", tx_hash))?;
+    let Some(first) = seeds.first() else {
+        bail!(
+            "Transaction {} has no transfers of the given tokens",
+            tx_hash
+        );
+    };
+    info!(
+        "Seeding traversal with {} transfers from transaction {}",
+        seeds.len(),
+        tx_hash
+    );
+
+    let mut builder = GraphBuilder::new(
+        data_source,
+        first.from_address,
+        first.block_number,
+        block_end,
+        token_addresses,
+        max_depth,
+        options,
+    );
+    // Every recipient gets traced, including the root if funds come back to it
+    builder.visited.clear();
+    let (_, tier) = builder.add_transfers(0, seeds)?;
+    let status = run_tiers(&mut builder, tier, 1)?;
+    Ok(builder.finish(status, Vec::new()))
+}
+
+/// Blocks `build_token_transfer_graph` asks the data source for at a time, so a cancelled
+/// build keeps what it already scanned.
+pub const TOKEN_SCAN_WINDOW: BlockNumber = 200_000;
+
+/// Build the complete transfer graph of `token_addresses` between two blocks, with no root:
+/// every transfer of the tokens in range becomes an edge, e.g. to map a honeypot token.
+///
+/// Only the options' policy edge filter and cancellation apply; there's nothing to expand.
+/// Nodes are added in order of each address's first transfer, and edges in time order.
+///
+/// The range is scanned in windows of `TOKEN_SCAN_WINDOW` blocks. If the build is cancelled or
+/// times out, the graph holds every transfer from the windows scanned before that.
+pub fn build_token_transfer_graph<D: TransferDataSource>(
+    data_source: &D,
+    token_addresses: &[Address],
+    block_start: BlockNumber,
+    block_end: BlockNumber,
+    options: &TraversalOptions,
+) -> Result<TraversalResult> {
     let mut graph = TransferGraph::new();
-    // stack keeps track of addresses + depth of my BFS
-    let mut stack: VecDeque<(Address, usize)> = VecDeque::new();
-    // addr_idx_map maps addresses to their node index in the graph so that I can insert edges
-    let mut addr_idx_map: HashMap<Address, NodeIndex> = HashMap::new();
-    // visited keeps track of addresses that have been visited
-    let mut visited: HashSet<Address> = HashSet::new();
-
-    let root_idx = graph.add_node(root_address.clone());
-    addr_idx_map.insert(root_address.clone(), root_idx);
-    stack.push_back((root_address.clone(), 0));
-    visited.insert(root_address.clone());
-
-    while let Some((curr_addr, depth)) = stack.pop_front() {
-        if depth > max_depth {
+    let mut status = TraversalStatus::Complete;
+
+    let mut transfers = Vec::new();
+    let mut window_start = block_start;
+    while window_start <= block_end {
+        if options.is_cancelled() {
+            status = options.stop_status();
+            break;
+        }
+        let window_end = window_start
+            .saturating_add(TOKEN_SCAN_WINDOW - 1)
+            .min(block_end);
+        match data_source.get_token_transfers(token_addresses, &window_start, &window_end) {
+            Ok(scanned) => transfers.extend(scanned),
+            Err(e) => {
+                status = options.stop_on_cancel(e)?;
+                break;
+            }
+        }
+        if window_end == block_end {
+            break;
+        }
+        window_start = window_end + 1;
+    }
+    transfers.sort_by_key(|transfer| transfer.position());
+    info!(
+        "Building token graph from {} transfers of {} tokens",
+        transfers.len(),
+        token_addresses.len()
+    );
+
+    for transfer in transfers {
+        if let Some(policy) = &options.policy
+            && !policy.include_edge(&transfer)
+        {
             continue;
         }
 
-        for transfer in
-            data_source.get_transfers(&curr_addr, &token_addresses, &block_start, &block_end)?
+        let from_idx = graph.add_node(TransferNode::new(transfer.from_address));
+        let to_idx = graph.add_node(TransferNode::new(transfer.to_address));
+
+        graph.add_edge(
+            from_idx,
+            to_idx,
+            TransferEdge {
+                amount: transfer.amount,
+                tx_hash: transfer.tx_hash,
+                block_number: transfer.block_number,
+                log_index: transfer.log_index,
+                token: transfer.token,
+            },
+        );
+    }
+
+    Ok(TraversalResult {
+        status,
+        graph,
+        truncated: Vec::new(),
+        frontier: Vec::new(),
+    })
+}
+
+// Expand `tier`, found at `first_depth`, and every tier after it down to the builder's max depth.
+// Checkpoints after each tier and resumes from an existing checkpoint when the options say so.
+fn run_tiers<D: TransferDataSource>(
+    builder: &mut GraphBuilder<D>,
+    mut tier: Vec<Address>,
+    mut first_depth: usize,
+) -> Result<TraversalStatus> {
+    let options = builder.options;
+    let max_depth = builder.max_depth;
+    let mut status = TraversalStatus::Complete;
+
+    if let Some(path) = &options.checkpoint_path
+        && options.resume
+        && path.exists()
+    {
+        let checkpoint = Checkpoint::load(path)?;
+        checkpoint.params.ensure_matches(&builder.params())?;
+        info!(
+            "Resuming from checkpoint {} at depth {} with {} nodes",
+            path.display(),
+            checkpoint.next_depth,
+            checkpoint.nodes.len()
+        );
+        first_depth = checkpoint.next_depth;
+        tier = builder.restore(checkpoint);
+    }
+
+    // tier holds the addresses at the current depth of my BFS, in discovery order
+    'tiers: for depth in first_depth..=max_depth {
+        if tier.is_empty() {
+            break;
+        }
+
+        // Fetch the whole tier before merging any of it, so that parallel and sequential runs
+        // see the same state and build the same graph
+        let fetched: Vec<Result<Fetched>> = if options.parallel {
+            tier.par_iter()
+                .map(|address| builder.fetch_raw(*address, depth))
+                .collect()
+        } else {
+            tier.iter()
+                .map(|address| builder.fetch_raw(*address, depth))
+                .collect()
+        };
+
+        let mut next_tier = Vec::new();
+        for (address, fetched) in tier.into_iter().zip(fetched) {
+            // Merge in order up to the first query that was cancelled
+            let fetched = match fetched {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    status = options.stop_on_cancel(e)?;
+                    break 'tiers;
+                }
+            };
+            let Some(transfers) = builder.accept(address, depth, fetched) else {
+                continue;
+            };
+            let (_, discovered) = builder.add_transfers(depth, transfers)?;
+            next_tier.extend(discovered);
+        }
+        tier = next_tier;
+
+        if let Some(path) = &options.checkpoint_path {
+            builder.checkpoint(depth + 1, &tier).save(path)?;
+        }
+
+        if let Some(observer) = &options.observer {
+            observer.on_depth_completed(
+                depth,
+                builder.graph.node_count(),
+                builder.graph.edge_count(),
+            );
+        }
+    }
+
+    Ok(status)
+}
+
+/// FrontierNode
+///
+/// A discovered address waiting to be expanded by the best-first traversal.
+#[derive(Debug, Clone)]
+pub struct FrontierNode {
+    pub address: Address,
+    pub depth: usize,
+    /// Sum of raw amounts of the graph's transfers into this address, across tokens.
+    pub inflow: U256,
+    /// Number of the graph's transfers into this address.
+    pub inflow_transfers: usize,
+}
+
+/// FrontierScore
+///
+/// Ranks frontier nodes for `build_transfer_graph_best_first`; higher scores are expanded first.
+pub trait FrontierScore: Send + Sync {
+    fn score(&self, node: &FrontierNode) -> U256;
+}
+
+/// InflowAmount
+///
+/// Expands the addresses that received the most first. Raw amounts are summed across tokens,
+/// so mixing tokens with different decimals skews the order.
+pub struct InflowAmount;
+
+impl FrontierScore for InflowAmount {
+    fn score(&self, node: &FrontierNode) -> U256 {
+        node.inflow
+    }
+}
+
+/// InflowCount
+///
+/// Expands the addresses that received the most transfers first.
+pub struct InflowCount;
+
+impl FrontierScore for InflowCount {
+    fn score(&self, node: &FrontierNode) -> U256 {
+        U256::from(node.inflow_transfers)
+    }
+}
+
+/// TraversalBudget
+///
+/// Caps on the work `build_transfer_graph_best_first` may do. Budgets are checked before each
+/// expansion, so the last expanded node can overshoot the node and edge caps.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TraversalBudget {
+    pub max_nodes: Option<usize>,
+    pub max_edges: Option<usize>,
+    pub max_queries: Option<usize>,
+}
+
+impl TraversalBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_nodes(self, max_nodes: usize) -> Self {
+        Self {
+            max_nodes: Some(max_nodes),
+            ..self
+        }
+    }
+
+    pub fn with_max_edges(self, max_edges: usize) -> Self {
+        Self {
+            max_edges: Some(max_edges),
+            ..self
+        }
+    }
+
+    pub fn with_max_queries(self, max_queries: usize) -> Self {
+        Self {
+            max_queries: Some(max_queries),
+            ..self
+        }
+    }
+
+    fn exhausted(&self, nodes: usize, edges: usize, queries: usize) -> bool {
+        self.max_nodes.is_some_and(|max| nodes >= max)
+            || self.max_edges.is_some_and(|max| edges >= max)
+            || self.max_queries.is_some_and(|max| queries >= max)
+    }
+}
+
+/// Like `build_transfer_graph_with_options`, but instead of going tier by tier, always expands
+/// the highest-scoring frontier node next and stops once `budget` runs out.
+///
+/// Whatever is left in the frontier is returned in `TraversalResult::frontier`, best first.
+#[allow(clippy::too_many_arguments)]
+pub fn build_transfer_graph_best_first<D: TransferDataSource>(
+    data_source: &D,
+    root_address: Address,
+    block_start: BlockNumber,
+    block_end: BlockNumber,
+    token_addresses: &[Address],
+    max_depth: usize,
+    options: &TraversalOptions,
+    budget: TraversalBudget,
+    scorer: &dyn FrontierScore,
+) -> Result<TraversalResult> {
+    let mut builder = GraphBuilder::new(
+        data_source,
+        root_address,
+        block_start,
+        block_end,
+        token_addresses,
+        max_depth,
+        options,
+    );
+    // frontier holds every discovered, not yet expanded address
+    let mut frontier: HashMap<Address, FrontierNode> = HashMap::new();
+    // heap entries go stale when a node's inflow grows; the latest score lives in `frontier`
+    let mut heap: BinaryHeap<(U256, Reverse<usize>, Address)> = BinaryHeap::new();
+    heap.push((U256::MAX, Reverse(0), root_address));
+    frontier.insert(
+        root_address,
+        FrontierNode {
+            address: root_address,
+            depth: 0,
+            inflow: U256::ZERO,
+            inflow_transfers: 0,
+        },
+    );
+
+    let mut status = TraversalStatus::Complete;
+
+    while let Some((score, _, curr_addr)) = heap.pop() {
+        let Some(node) = frontier.get(&curr_addr) else {
+            continue;
+        };
+        if curr_addr != root_address && score != scorer.score(node) {
+            continue;
+        }
+        if budget.exhausted(
+            builder.graph.node_count(),
+            builder.graph.edge_count(),
+            builder.queries,
+        ) {
+            status = TraversalStatus::BudgetExhausted;
+            break;
+        }
+
+        let depth = node.depth;
+        let transfers = match builder.fetch(curr_addr, depth) {
+            Ok(Some(transfers)) => transfers,
+            Ok(None) => {
+                frontier.remove(&curr_addr);
+                continue;
+            }
+            // Leave the node in the frontier, it wasn't explored
+            Err(e) => {
+                status = options.stop_on_cancel(e)?;
+                break;
+            }
+        };
+        let node = frontier.remove(&curr_addr).expect("checked above");
+        let (included, discovered) = builder.add_transfers(node.depth, transfers)?;
+        for address in discovered {
+            frontier.insert(
+                address,
+                FrontierNode {
+                    address,
+                    depth: node.depth + 1,
+                    inflow: U256::ZERO,
+                    inflow_transfers: 0,
+                },
+            );
+        }
+        // Inflow counts toward every node still waiting in the frontier
+        for transfer in included {
+            let Some(entry) = frontier.get_mut(&transfer.to_address) else {
+                continue;
+            };
+            entry.inflow = entry.inflow.saturating_add(transfer.amount);
+            entry.inflow_transfers += 1;
+            heap.push((scorer.score(entry), Reverse(entry.depth), entry.address));
+        }
+    }
+
+    let mut remaining: Vec<FrontierNode> = frontier.into_values().collect();
+    remaining.sort_by(|a, b| {
+        scorer
+            .score(b)
+            .cmp(&scorer.score(a))
+            .then(a.address.cmp(&b.address))
+    });
+
+    Ok(builder.finish(status, remaining))
+}
+
+// What a data source query for one address came back with
+pub(crate) enum Fetched {
+    OutOfRange,
+    OverThreshold { threshold: usize },
+    Transfers(Vec<Transfer>),
+}
+
+// Graph state shared by the traversal strategies: adds a node's transfers to the graph while
+// applying the temporal window, expansion policy and hub cutoff from `TraversalOptions`
+pub(crate) struct GraphBuilder<'a, D: TransferDataSource> {
+    data_source: &'a D,
+    root_address: Address,
+    block_start: BlockNumber,
+    block_end: BlockNumber,
+    token_addresses: &'a [Address],
+    max_depth: usize,
+    options: &'a TraversalOptions,
+    pub(crate) graph: TransferGraph,
+    // visited keeps track of addresses that have been queued for expansion
+    pub(crate) visited: HashSet<Address>,
+    // arrivals keeps the earliest (block, log index) at which funds reached each address.
+    // Only used in temporal mode; the root has no arrival and is expanded over the full range.
+    pub(crate) arrivals: HashMap<Address, (BlockNumber, u64)>,
+    // truncated keeps hubs we found and didn't expand
+    pub(crate) truncated: Vec<TruncatedNode>,
+    // transfer counts of expanded nodes, kept sorted, for a learned hub cutoff
+    pub(crate) expanded_counts: Vec<usize>,
+    // expanded keeps the addresses whose transfers made it into the graph
+    expanded: HashSet<Address>,
+    // number of data source queries made so far
+    queries: usize,
+}
+
+impl<'a, D: TransferDataSource> GraphBuilder<'a, D> {
+    pub(crate) fn new(
+        data_source: &'a D,
+        root_address: Address,
+        block_start: BlockNumber,
+        block_end: BlockNumber,
+        token_addresses: &'a [Address],
+        max_depth: usize,
+        options: &'a TraversalOptions,
+    ) -> Self {
+        let mut graph = TransferGraph::new();
+        graph.add_node(
+            TransferNode::new(root_address)
+                .with_depth(0)
+                .with_root(root_address),
+        );
+
+        Self {
+            data_source,
+            root_address,
+            block_start,
+            block_end,
+            token_addresses,
+            max_depth,
+            options,
+            graph,
+            visited: HashSet::from([root_address]),
+            arrivals: HashMap::new(),
+            truncated: Vec::new(),
+            expanded_counts: Vec::new(),
+            expanded: HashSet::new(),
+            queries: 0,
+        }
+    }
+
+    // Block range to query for an address, narrowed to after its arrival in temporal mode
+    fn query_range(&self, address: &Address) -> Option<(BlockNumber, BlockNumber)> {
+        let arrival = self.arrivals.get(address).copied();
+        let (query_start, query_end) = match (&self.options.temporal, arrival) {
+            (Some(window), Some((arrival_block, _))) => (
+                arrival_block.max(self.block_start),
+                window.max_hop_blocks.map_or(self.block_end, |blocks| {
+                    self.block_end.min(arrival_block.saturating_add(blocks))
+                }),
+            ),
+            _ => (self.block_start, self.block_end),
+        };
+        (query_start <= query_end).then_some((query_start, query_end))
+    }
+
+    // Query the data source for an address without touching the builder's state, so a whole
+    // tier can be fetched in parallel. Temporal filtering uses the arrivals as they stand now.
+    pub(crate) fn fetch_raw(&self, address: Address, depth: usize) -> Result<Fetched> {
+        if self.options.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        let Some((query_start, query_end)) = self.query_range(&address) else {
+            return Ok(Fetched::OutOfRange);
+        };
+        if let Some(observer) = &self.options.observer {
+            observer.on_node_dequeued(&address, depth);
+        }
+
+        let mut transfers = match self.options.hub_cutoff {
+            Some(HubCutoff::TransferCount(threshold)) if address != self.root_address => {
+                match self.data_source.get_transfers_up_to(
+                    &address,
+                    self.token_addresses,
+                    &query_start,
+                    &query_end,
+                    threshold,
+                )? {
+                    Some(transfers) => transfers,
+                    None => return Ok(Fetched::OverThreshold { threshold }),
+                }
+            }
+            _ => self.data_source.get_transfers(
+                &address,
+                self.token_addresses,
+                &query_start,
+                &query_end,
+            )?,
+        };
+
+        if let Some(observer) = &self.options.observer {
+            observer.on_transfers_fetched(&address, depth, transfers.len());
+        }
+
+        // In temporal mode, funds can't leave an address before they arrived
+        if let Some(arrival) = self.arrivals.get(&address) {
+            transfers.retain(|transfer| transfer.position() >= *arrival);
+        }
+
+        Ok(Fetched::Transfers(transfers))
+    }
+
+    // Record a fetch result, returning the transfers to add or None if the node is a hub or had
+    // nothing in range
+    pub(crate) fn accept(
+        &mut self,
+        address: Address,
+        depth: usize,
+        fetched: Fetched,
+    ) -> Option<Vec<Transfer>> {
+        let transfers = match fetched {
+            Fetched::OutOfRange => return None,
+            Fetched::OverThreshold { threshold } => {
+                self.queries += 1;
+                self.truncated.push(TruncatedNode {
+                    address,
+                    depth,
+                    reason: TruncationReason::TransferCount { threshold },
+                });
+                return None;
+            }
+            Fetched::Transfers(transfers) => transfers,
+        };
+        self.queries += 1;
+
+        if let Some(HubCutoff::Learned {
+            multiplier,
+            min_samples,
+            floor,
+        }) = self.options.hub_cutoff
         {
-            let from = transfer.from_address.clone();
-            let to = transfer.to_address.clone();
-
-            // This code checks our addr_idx_map to see if we've already seen this address
-            // If we have seen this address (i.e., .entry() returns an Entry::Occupied), `.entry().or_insert_with()` will return the existing node index
-            // If we haven't seen this address (i.e., .entry() returns an Entry::Vacant), `.or_insert_with()` will
-            // (1) add the address as a node in the graph (which returns a NodeIndex),
-            // (2) add the NodeIndex to add_idx_map,
-            // (3) return a mutable reference to a NodeIndex
-            // (4) dereference the mutable reference to get the NodeIndex (required, at least, to avoid maintaining a mutable reference to the NodeIndex in addr_idx_map)
-            let from_idx = *addr_idx_map
-                .entry(from.clone())
-                .or_insert_with(|| graph.add_node(from.clone()));
-            let to_idx = *addr_idx_map
-                .entry(to.clone())
-                .or_insert_with(|| graph.add_node(to.clone()));
-
-            graph.add_edge(
+            if address != self.root_address && self.expanded_counts.len() >= min_samples {
+                let cutoff = learned_cutoff(&self.expanded_counts, multiplier).max(floor);
+                if transfers.len() > cutoff {
+                    self.truncated.push(TruncatedNode {
+                        address,
+                        depth,
+                        reason: TruncationReason::LearnedCutoff {
+                            count: transfers.len(),
+                            cutoff,
+                        },
+                    });
+                    return None;
+                }
+            }
+            let position = self
+                .expanded_counts
+                .partition_point(|count| *count <= transfers.len());
+            self.expanded_counts.insert(position, transfers.len());
+        }
+
+        self.expanded.insert(address);
+        Some(transfers)
+    }
+
+    // Fetch an address's transfers, or None if it has nothing in range or is a hub
+    fn fetch(&mut self, address: Address, depth: usize) -> Result<Option<Vec<Transfer>>> {
+        let fetched = self.fetch_raw(address, depth)?;
+        Ok(self.accept(address, depth, fetched))
+    }
+
+    // Add transfers made by a node at `depth` to the graph. Returns the transfers that made it
+    // in, and the recipients discovered for the first time that should be expanded next.
+    pub(crate) fn add_transfers(
+        &mut self,
+        depth: usize,
+        transfers: Vec<Transfer>,
+    ) -> Result<(Vec<Transfer>, Vec<Address>)> {
+        let mut included = Vec::new();
+        let mut discovered = Vec::new();
+
+        for transfer in transfers {
+            if let Some(policy) = &self.options.policy
+                && !policy.include_edge(&transfer)
+            {
+                continue;
+            }
+
+            let from = transfer.from_address;
+            let to = transfer.to_address;
+
+            // add_node returns the existing node index if we've already seen this address, so
+            // the depth and root only stick for addresses seen here for the first time
+            let from_idx = self.graph.add_node(
+                TransferNode::new(from)
+                    .with_depth(depth)
+                    .with_root(self.root_address),
+            );
+            let to_is_new = !self.graph.contains(&to);
+            let to_idx = self.graph.add_node(
+                TransferNode::new(to)
+                    .with_depth(depth + 1)
+                    .with_root(self.root_address),
+            );
+
+            let edge_idx = self.graph.add_edge(
                 from_idx,
                 to_idx,
                 TransferEdge {
                     amount: transfer.amount,
                     tx_hash: transfer.tx_hash,
                     block_number: transfer.block_number,
+                    log_index: transfer.log_index,
                     token: transfer.token,
                 },
             );
+            if to_is_new {
+                self.graph[to_idx].discovered_by = Some(edge_idx);
+            }
+
+            // Keep the earliest arrival; a node reached by several parents in the same tier is
+            // expanded from the first time any of them paid it
+            if self.options.temporal.is_some() && to != self.root_address {
+                self.arrivals
+                    .entry(to)
+                    .and_modify(|earliest| *earliest = (*earliest).min(transfer.position()))
+                    .or_insert(transfer.position());
+            }
 
-            if depth < max_depth && visited.insert(to.clone()) {
-                stack.push_back((to, depth + 1));
+            if depth < self.max_depth && self.visited.insert(to) {
+                // Nodes the policy won't expand stay in the graph as leaves
+                let expand = match &self.options.policy {
+                    Some(policy) => policy.expand_node(&to, depth + 1, self.data_source)?,
+                    None => true,
+                };
+                if expand {
+                    discovered.push(to);
+                }
             }
+            included.push(transfer);
+        }
+
+        Ok((included, discovered))
+    }
+
+    fn params(&self) -> TraversalParams {
+        TraversalParams {
+            root_address: self.root_address,
+            block_start: self.block_start,
+            block_end: self.block_end,
+            token_addresses: self.token_addresses.to_vec(),
+            max_depth: self.max_depth,
+            temporal: self.options.temporal,
+            hub_cutoff: self.options.hub_cutoff,
+        }
+    }
+
+    fn checkpoint(&self, next_depth: usize, tier: &[Address]) -> Checkpoint {
+        Checkpoint {
+            params: self.params(),
+            next_depth,
+            tier: tier.to_vec(),
+            nodes: self.graph.node_weights().cloned().collect(),
+            edges: self
+                .graph
+                .edge_references()
+                .map(|edge| {
+                    (
+                        edge.source().index(),
+                        edge.target().index(),
+                        edge.weight().clone(),
+                    )
+                })
+                .collect(),
+            visited: self.visited.iter().copied().collect(),
+            arrivals: self
+                .arrivals
+                .iter()
+                .map(|(address, arrival)| (*address, *arrival))
+                .collect(),
+            truncated: self.truncated.clone(),
+            expanded_counts: self.expanded_counts.clone(),
+            expanded: self.expanded.iter().copied().collect(),
+            queries: self.queries,
+        }
+    }
+
+    // Replace the builder's state with a checkpoint's, returning the tier to expand next
+    fn restore(&mut self, checkpoint: Checkpoint) -> Vec<Address> {
+        let mut graph = TransferGraph::new();
+        for node in checkpoint.nodes {
+            graph.add_node(node);
+        }
+        for (source, target, edge) in checkpoint.edges {
+            graph.add_edge(NodeIndex::new(source), NodeIndex::new(target), edge);
         }
+
+        self.graph = graph;
+        self.visited = checkpoint.visited.into_iter().collect();
+        self.arrivals = checkpoint.arrivals.into_iter().collect();
+        self.truncated = checkpoint.truncated;
+        self.expanded_counts = checkpoint.expanded_counts;
+        self.expanded_counts.sort_unstable();
+        self.expanded = checkpoint.expanded.into_iter().collect();
+        self.queries = checkpoint.queries;
+        checkpoint.tier
+    }
+
+    fn finish(mut self, status: TraversalStatus, frontier: Vec<FrontierNode>) -> TraversalResult {
+        flag_nodes(&mut self.graph, &self.expanded, &self.truncated);
+        TraversalResult {
+            status,
+            graph: self.graph,
+            truncated: self.truncated,
+            frontier,
+        }
+    }
+}
+
+// Set the hub and unexplored flags from what the traversal did
+pub(crate) fn flag_nodes(
+    graph: &mut TransferGraph,
+    expanded: &HashSet<Address>,
+    truncated: &[TruncatedNode],
+) {
+    let hubs: HashSet<Address> = truncated.iter().map(|node| node.address).collect();
+    for node in graph.node_weights_mut() {
+        node.flags.hub = hubs.contains(&node.address);
+        node.flags.unexplored = !expanded.contains(&node.address);
     }
+}
+
+/// Set the contract flag on every node, asking the data source about each address.
+pub fn flag_contracts<D: TransferDataSource>(
+    graph: &mut TransferGraph,
+    data_source: &D,
+) -> Result<()> {
+    for node in graph.node_weights_mut() {
+        node.flags.contract = data_source.is_contract(&node.address)?;
+    }
+    Ok(())
+}
+
+// multiplier x the median of the transfer counts seen so far, which must be sorted
+fn learned_cutoff(counts: &[usize], multiplier: f64) -> usize {
+    let median = counts[counts.len() / 2];
+    (median as f64 * multiplier).ceil() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
 
-    Ok(graph)
+    #[test]
+    fn transfers_up_to_stops_past_the_limit() {
+        let source = MemorySource::new(vec![
+            transfer(1, 2, 10, 0, 5),
+            transfer(1, 3, 11, 0, 5),
+            transfer(1, 4, 12, 0, 5),
+        ]);
+        let up_to = |limit| {
+            source
+                .get_transfers_up_to(&address(1), &[TOKEN], &0, &100, limit)
+                .unwrap()
+        };
+
+        assert_eq!(up_to(3).map(|transfers| transfers.len()), Some(3));
+        assert!(up_to(2).is_none());
+    }
+
+    #[test]
+    fn transfer_count_cutoff_truncates_hubs_but_not_the_root() {
+        let source = MemorySource::new(vec![
+            transfer(1, 2, 10, 0, 5),
+            transfer(1, 3, 10, 1, 5),
+            transfer(1, 4, 10, 2, 5),
+            transfer(2, 5, 11, 0, 5),
+            transfer(2, 6, 11, 1, 5),
+            transfer(2, 7, 11, 2, 5),
+            transfer(3, 8, 12, 0, 5),
+        ]);
+        let options = TraversalOptions::new().with_hub_cutoff(HubCutoff::TransferCount(2));
+        let result =
+            build_transfer_graph_with_options(&source, address(1), 0, 100, &[TOKEN], 3, &options)
+                .unwrap();
+
+        // The root has three transfers, over the threshold, and is expanded anyway
+        assert_eq!(result.graph.neighbors_out(&address(1)).len(), 3);
+        assert_eq!(result.truncated.len(), 1);
+        assert_eq!(result.truncated[0].address, address(2));
+        assert_eq!(result.truncated[0].depth, 1);
+        assert_eq!(
+            result.truncated[0].reason,
+            TruncationReason::TransferCount { threshold: 2 }
+        );
+        assert!(result.graph.node(&address(2)).unwrap().flags.hub);
+        assert!(!result.graph.contains(&address(5)));
+        assert_eq!(result.graph.neighbors_out(&address(3)), vec![address(8)]);
+    }
+
+    #[test]
+    fn learned_cutoff_uses_the_median_so_far() {
+        let mut transfers = vec![
+            transfer(1, 2, 10, 0, 5),
+            transfer(1, 3, 10, 1, 5),
+            transfer(1, 4, 10, 2, 5),
+            transfer(1, 5, 10, 3, 5),
+            transfer(2, 10, 11, 0, 5),
+            transfer(3, 11, 11, 1, 5),
+            transfer(5, 12, 11, 2, 5),
+        ];
+        transfers.extend((20..29).map(|to| transfer(4, to, 12, to as u64, 5)));
+        let source = MemorySource::new(transfers);
+        let options = TraversalOptions::new().with_hub_cutoff(HubCutoff::Learned {
+            multiplier: 2.0,
+            min_samples: 2,
+            floor: 1,
+        });
+        let result =
+            build_transfer_graph_with_options(&source, address(1), 0, 100, &[TOKEN], 2, &options)
+                .unwrap();
+
+        // Counts before address 4 are [1, 1, 4], so the cutoff is 2 x 1
+        assert_eq!(result.truncated.len(), 1);
+        assert_eq!(result.truncated[0].address, address(4));
+        assert_eq!(
+            result.truncated[0].reason,
+            TruncationReason::LearnedCutoff {
+                count: 9,
+                cutoff: 2
+            }
+        );
+        assert!(result.graph.contains(&address(12)));
+    }
+
+    // Addresses 2 and 3 both pay 6 and 7, in different orders and blocks, so the merge order
+    // decides depths, discovering edges and temporal arrivals
+    fn diamond_source() -> MemorySource {
+        MemorySource::new(vec![
+            transfer(1, 2, 10, 0, 5),
+            transfer(1, 3, 10, 1, 5),
+            transfer(1, 4, 11, 0, 5),
+            transfer(3, 6, 12, 0, 5),
+            transfer(2, 7, 13, 0, 5),
+            transfer(2, 6, 14, 0, 5),
+            transfer(3, 7, 15, 0, 5),
+            transfer(4, 8, 9, 0, 5),
+            transfer(4, 9, 16, 0, 5),
+            transfer(4, 10, 16, 1, 5),
+            transfer(4, 11, 16, 2, 5),
+            transfer(6, 12, 13, 0, 5),
+            transfer(6, 13, 17, 0, 5),
+            transfer(7, 2, 18, 0, 5),
+        ])
+    }
+
+    #[test]
+    fn parallel_and_sequential_builds_match() {
+        let source = diamond_source();
+        let modes = [
+            TraversalOptions::new(),
+            TraversalOptions::new().with_temporal(TemporalWindow::new()),
+        ];
+        for options in modes {
+            let options = options.with_hub_cutoff(HubCutoff::TransferCount(2));
+            let build = |parallel| {
+                let options = options.clone().with_parallel(parallel);
+                build_transfer_graph_with_options(
+                    &source,
+                    address(1),
+                    0,
+                    100,
+                    &[TOKEN],
+                    3,
+                    &options,
+                )
+                .unwrap()
+            };
+            let sequential = build(false);
+            let parallel = build(true);
+
+            let nodes = |result: &TraversalResult| {
+                result
+                    .graph
+                    .node_weights()
+                    .map(|node| (node.address, node.depth, node.discovered_by, node.flags))
+                    .collect::<Vec<_>>()
+            };
+            let edges = |result: &TraversalResult| {
+                result
+                    .graph
+                    .edge_references()
+                    .map(|edge| {
+                        (
+                            edge.source(),
+                            edge.target(),
+                            edge.weight().tx_hash,
+                            edge.weight().log_index,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let truncated = |result: &TraversalResult| {
+                result
+                    .truncated
+                    .iter()
+                    .map(|node| (node.address, node.depth, node.reason.clone()))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(nodes(&sequential), nodes(&parallel));
+            assert_eq!(edges(&sequential), edges(&parallel));
+            assert_eq!(truncated(&sequential), truncated(&parallel));
+            assert_eq!(truncated(&sequential).len(), 1);
+        }
+    }
+
+    #[test]
+    fn temporal_mode_skips_transfers_before_arrival() {
+        let source = diamond_source();
+        let options = TraversalOptions::new().with_temporal(TemporalWindow::new());
+        let result =
+            build_transfer_graph_with_options(&source, address(1), 0, 100, &[TOKEN], 3, &options)
+                .unwrap();
+
+        // 4 paid 8 at block 9, before it was paid at block 11
+        assert!(!result.graph.contains(&address(8)));
+        // 6 was first paid at block 12, so its block 13 transfer counts
+        assert!(result.graph.contains(&address(12)));
+        assert_eq!(result.graph.node(&address(6)).unwrap().depth, Some(2));
+    }
+
+    #[test]
+    fn cancelled_builds_return_the_partial_graph() {
+        let source = diamond_source();
+        let token = CancellationToken::new();
+        let options = TraversalOptions::new()
+            .with_observer(Arc::new(CancelAfterDepth {
+                depth: 0,
+                token: token.clone(),
+            }))
+            .with_cancellation(token);
+        let result =
+            build_transfer_graph_with_options(&source, address(1), 0, 100, &[TOKEN], 3, &options)
+                .unwrap();
+
+        // Only the root was expanded before the token fired
+        assert_eq!(result.status, TraversalStatus::Cancelled);
+        assert_eq!(result.graph.node_count(), 4);
+        assert_eq!(result.graph.edge_count(), 3);
+        assert!(result.graph.node(&address(2)).unwrap().flags.unexplored);
+        assert!(!result.graph.node(&address(1)).unwrap().flags.unexplored);
+
+        let expired = TraversalOptions::new()
+            .with_cancellation(CancellationToken::new().with_timeout(std::time::Duration::ZERO));
+        let result =
+            build_transfer_graph_with_options(&source, address(1), 0, 100, &[TOKEN], 3, &expired)
+                .unwrap();
+        assert_eq!(result.status, TraversalStatus::TimedOut);
+        assert_eq!(result.graph.node_count(), 1);
+    }
+
+    #[test]
+    fn tx_seed_traces_every_recipient_including_the_root() {
+        // 1 swaps through 2 and 3 in one transaction, getting funds back from 3
+        let swap = B256::repeat_byte(0xaa);
+        let in_swap = |from, to, log_index| Transfer {
+            tx_hash: swap,
+            ..transfer(from, to, 10, log_index, 5)
+        };
+        let source = MemorySource::new(vec![
+            in_swap(1, 2, 0),
+            in_swap(2, 3, 1),
+            in_swap(3, 1, 2),
+            transfer(1, 4, 9, 0, 5),
+            transfer(1, 5, 11, 0, 5),
+            transfer(3, 6, 12, 0, 5),
+        ]);
+        let result =
+            build_transfer_graph_from_tx(&source, swap, 100, &[TOKEN], 2, &TraversalOptions::new())
+                .unwrap();
+
+        assert_eq!(result.status, TraversalStatus::Complete);
+        assert_eq!(result.graph.node(&address(1)).unwrap().depth, Some(0));
+        // The root's own transfers are traced from the transaction's block on
+        assert!(result.graph.contains(&address(5)));
+        assert!(!result.graph.contains(&address(4)));
+        assert_eq!(result.graph.node(&address(6)).unwrap().depth, Some(2));
+
+        let missing = build_transfer_graph_from_tx(
+            &source,
+            B256::ZERO,
+            100,
+            &[TOKEN],
+            2,
+            &TraversalOptions::new(),
+        );
+        assert!(missing.is_err());
+    }
+
+    // Records the order addresses are queried in
+    #[derive(Default)]
+    struct Dequeued(std::sync::Mutex<Vec<Address>>);
+
+    impl TraversalObserver for Dequeued {
+        fn on_node_dequeued(&self, address: &Address, _depth: usize) {
+            self.0.lock().unwrap().push(*address);
+        }
+    }
+
+    #[test]
+    fn best_first_expands_the_largest_inflow_until_out_of_queries() {
+        let source = MemorySource::new(vec![
+            transfer(1, 2, 1, 0, 5),
+            transfer(1, 3, 1, 1, 50),
+            transfer(1, 4, 1, 2, 20),
+            transfer(2, 5, 2, 0, 1000),
+            transfer(3, 6, 2, 1, 1),
+            transfer(4, 7, 2, 2, 100),
+        ]);
+        let dequeued = Arc::new(Dequeued::default());
+        let options = TraversalOptions::new().with_observer(dequeued.clone());
+        let result = build_transfer_graph_best_first(
+            &source,
+            address(1),
+            0,
+            100,
+            &[TOKEN],
+            3,
+            &options,
+            TraversalBudget::new().with_max_queries(3),
+            &InflowAmount,
+        )
+        .unwrap();
+
+        assert_eq!(result.status, TraversalStatus::BudgetExhausted);
+        assert_eq!(
+            *dequeued.0.lock().unwrap(),
+            vec![address(1), address(3), address(4)]
+        );
+        let frontier: Vec<(Address, U256)> = result
+            .frontier
+            .iter()
+            .map(|node| (node.address, node.inflow))
+            .collect();
+        assert_eq!(
+            frontier,
+            vec![
+                (address(7), U256::from(100)),
+                (address(2), U256::from(5)),
+                (address(6), U256::from(1))
+            ]
+        );
+        assert!(!result.graph.contains(&address(5)));
+        assert!(result.graph.node(&address(2)).unwrap().flags.unexplored);
+    }
 }
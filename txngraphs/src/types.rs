@@ -2,36 +2,442 @@ use alloy_primitives::{
     Address,
     aliases::{BlockNumber, TxHash, U256},
 };
-use petgraph::{Directed, graph::Graph};
+use petgraph::{
+    Directed, Direction,
+    algo::tarjan_scc,
+    graph::{EdgeIndex, EdgeReference, Graph, NodeIndex},
+    visit::EdgeRef,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, Index, IndexMut};
 use std::{fmt::Debug, fmt::Display};
 
 ///
 /// TransferGraph
 ///
-/// The graph is a directed graph where the nodes are addresses, as an &str,
-/// and the edges are transfers with certain characteristics.
-/// For edges, see `TransferEdge`.
-pub type TransferGraph = Graph<Address, TransferEdge, Directed>;
+/// The graph is a directed graph where the nodes are addresses, with what the traversal
+/// learned about them, and the edges are transfers with certain characteristics.
+/// For nodes, see `TransferNode`. For edges, see `TransferEdge`.
+///
+/// Wraps a petgraph `Graph` together with an address -> `NodeIndex` index, so nodes can be
+/// looked up by address. Read-only petgraph methods are available through `Deref`; changes
+/// go through `add_node`, `add_edge` and `remove_node`, which keep the index in sync.
+///
+#[derive(Debug, Clone, Default)]
+pub struct TransferGraph {
+    graph: Graph<TransferNode, TransferEdge, Directed>,
+    index: HashMap<Address, NodeIndex>,
+}
+
+impl TransferGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The underlying petgraph graph, for petgraph algorithms.
+    pub fn as_graph(&self) -> &Graph<TransferNode, TransferEdge, Directed> {
+        &self.graph
+    }
+
+    pub fn node_index(&self, address: &Address) -> Option<NodeIndex> {
+        self.index.get(address).copied()
+    }
+
+    pub fn node(&self, address: &Address) -> Option<&TransferNode> {
+        self.node_index(address).map(|idx| &self.graph[idx])
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.index.contains_key(address)
+    }
+
+    /// Add a node for a new address. If the address is already in the graph, `node` is
+    /// dropped and the existing node's index is returned.
+    pub fn add_node(&mut self, node: TransferNode) -> NodeIndex {
+        if let Some(idx) = self.node_index(&node.address) {
+            return idx;
+        }
+        let address = node.address;
+        let idx = self.graph.add_node(node);
+        self.index.insert(address, idx);
+        idx
+    }
+
+    pub fn add_edge(&mut self, from: NodeIndex, to: NodeIndex, edge: TransferEdge) -> EdgeIndex {
+        self.graph.add_edge(from, to, edge)
+    }
+
+    /// Remove an address and its transfers.
+    ///
+    /// petgraph moves the last node and edges into the freed slots, so indices held from
+    /// before the call may now point elsewhere. Each remaining node's `discovered_by` is
+    /// looked up again by its transfer, and cleared if that transfer was removed.
+    pub fn remove_node(&mut self, address: &Address) -> Option<TransferNode> {
+        let idx = self.node_index(address)?;
+
+        // Remember each discovering edge by (sender, tx hash, log index) to find it after
+        // the edges are shuffled
+        let discovered_by: HashMap<Address, (Address, TxHash, u64)> = self
+            .graph
+            .node_weights()
+            .filter_map(|node| {
+                let edge = node.discovered_by?;
+                let (source, _) = self.graph.edge_endpoints(edge)?;
+                let weight = &self.graph[edge];
+                Some((
+                    node.address,
+                    (self.graph[source].address, weight.tx_hash, weight.log_index),
+                ))
+            })
+            .collect();
+
+        self.index.remove(address);
+        let node = self.graph.remove_node(idx)?;
+        if let Some(moved) = self.graph.node_weight(idx) {
+            self.index.insert(moved.address, idx);
+        }
+
+        for idx in self.graph.node_indices() {
+            let edge = discovered_by.get(&self.graph[idx].address).and_then(
+                |(source, tx_hash, log_index)| {
+                    self.graph
+                        .edges_directed(idx, Direction::Incoming)
+                        .find(|edge| {
+                            self.graph[edge.source()].address == *source
+                                && edge.weight().tx_hash == *tx_hash
+                                && edge.weight().log_index == *log_index
+                        })
+                        .map(|edge| edge.id())
+                },
+            );
+            self.graph[idx].discovered_by = edge;
+        }
+        Some(node)
+    }
+
+    /// Node weights, mutably, e.g. to set flags or labels. Don't change their addresses.
+    pub fn node_weights_mut(&mut self) -> impl Iterator<Item = &mut TransferNode> {
+        self.graph.node_weights_mut()
+    }
+
+    /// Addresses `address` sent to, once each, in the order they were first paid.
+    pub fn neighbors_out(&self, address: &Address) -> Vec<Address> {
+        self.neighbors(address, Direction::Outgoing)
+    }
+
+    /// Addresses that sent to `address`, once each, in the order they first paid it.
+    pub fn neighbors_in(&self, address: &Address) -> Vec<Address> {
+        self.neighbors(address, Direction::Incoming)
+    }
+
+    fn neighbors(&self, address: &Address, direction: Direction) -> Vec<Address> {
+        let Some(idx) = self.node_index(address) else {
+            return Vec::new();
+        };
+        // petgraph walks edges newest first
+        let mut neighbors: Vec<Address> = self
+            .graph
+            .neighbors_directed(idx, direction)
+            .map(|idx| self.graph[idx].address)
+            .collect();
+        neighbors.reverse();
+        let mut seen = HashSet::new();
+        neighbors.retain(|address| seen.insert(*address));
+        neighbors
+    }
+
+    /// Transfers of `token`.
+    pub fn edges_for_token(
+        &self,
+        token: Address,
+    ) -> impl Iterator<Item = EdgeReference<'_, TransferEdge>> {
+        self.graph
+            .edge_references()
+            .filter(move |edge| edge.weight().token == token)
+    }
+
+    /// Transfers in blocks `block_start..=block_end`.
+    pub fn edges_in_blocks(
+        &self,
+        block_start: BlockNumber,
+        block_end: BlockNumber,
+    ) -> impl Iterator<Item = EdgeReference<'_, TransferEdge>> {
+        self.graph
+            .edge_references()
+            .filter(move |edge| (block_start..=block_end).contains(&edge.weight().block_number))
+    }
+
+    /// A new graph with only the transfers `keep` accepts, and the nodes they touch.
+    pub fn filter_edges(&self, keep: impl Fn(&TransferEdge) -> bool) -> TransferGraph {
+        let mut subgraph = TransferGraph::new();
+        for edge in self.graph.edge_references() {
+            if !keep(edge.weight()) {
+                continue;
+            }
+            let from = subgraph.add_node(self.graph[edge.source()].clone());
+            let to = subgraph.add_node(self.graph[edge.target()].clone());
+            subgraph.add_edge(from, to, edge.weight().clone());
+        }
+        subgraph.clear_discovered_by();
+        subgraph
+    }
+
+    /// A new graph with the given nodes and every transfer between them.
+    pub fn induced_subgraph(&self, nodes: &[NodeIndex]) -> TransferGraph {
+        let mut subgraph = TransferGraph::new();
+        let mut mapping: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+        for idx in nodes {
+            mapping.insert(*idx, subgraph.add_node(self.graph[*idx].clone()));
+        }
+        for edge in self.graph.edge_references() {
+            if let (Some(from), Some(to)) =
+                (mapping.get(&edge.source()), mapping.get(&edge.target()))
+            {
+                subgraph.add_edge(*from, *to, edge.weight().clone());
+            }
+        }
+        subgraph.clear_discovered_by();
+        subgraph
+    }
+
+    /// The subgraph within `hops` transfers of `address`, following transfers either way.
+    pub fn neighborhood(&self, address: &Address, hops: usize) -> TransferGraph {
+        let Some(start) = self.node_index(address) else {
+            return TransferGraph::new();
+        };
+        let mut nodes = vec![start];
+        let mut seen = HashSet::from([start]);
+        let mut tier = vec![start];
+        for _ in 0..hops {
+            let mut next_tier = Vec::new();
+            for idx in tier {
+                for neighbor in self.graph.neighbors_undirected(idx) {
+                    if seen.insert(neighbor) {
+                        nodes.push(neighbor);
+                        next_tier.push(neighbor);
+                    }
+                }
+            }
+            tier = next_tier;
+        }
+        self.induced_subgraph(&nodes)
+    }
+
+    /// Every group of addresses that funds can flow around, i.e. each strongly connected
+    /// component with more than one address, as its own graph with the transfers inside it.
+    pub fn closed_loops(&self) -> Vec<TransferGraph> {
+        let mut sccs = tarjan_scc(&self.graph);
+        sccs.retain(|scc| scc.len() > 1);
+
+        // Map each node to its loop and its index in the loop's graph, so that every edge is
+        // routed with two lookups instead of scanning the components
+        let mut loops: Vec<TransferGraph> = Vec::with_capacity(sccs.len());
+        let mut placement: HashMap<NodeIndex, (usize, NodeIndex)> = HashMap::new();
+        for (loop_id, scc) in sccs.iter().enumerate() {
+            let mut loop_graph = TransferGraph::new();
+            for idx in scc {
+                let loop_idx = loop_graph.add_node(self.graph[*idx].clone());
+                placement.insert(*idx, (loop_id, loop_idx));
+            }
+            loops.push(loop_graph);
+        }
+
+        for edge in self.graph.edge_references() {
+            if let (Some((source_loop, source)), Some((target_loop, target))) =
+                (placement.get(&edge.source()), placement.get(&edge.target()))
+                && source_loop == target_loop
+            {
+                loops[*source_loop].add_edge(*source, *target, edge.weight().clone());
+            }
+        }
+
+        for loop_graph in &mut loops {
+            loop_graph.clear_discovered_by();
+        }
+        loops
+    }
+
+    /// The chain of transfers, root first, through which the traversal first reached `address`.
+    ///
+    /// Follows each node's `discovered_by` edge back to a node without one. Returns `None` if
+    /// the address isn't in the graph, and an empty path for the root.
+    pub fn explain_path(&self, address: &Address) -> Option<Vec<EdgeIndex>> {
+        let mut node_idx = self.node_index(address)?;
+
+        let mut path = Vec::new();
+        while let Some(edge_idx) = self.graph[node_idx].discovered_by {
+            // Guard against a cycle of discovering edges in a hand-edited graph
+            if path.len() > self.graph.edge_count() {
+                break;
+            }
+            path.push(edge_idx);
+            let (source, _) = self.graph.edge_endpoints(edge_idx)?;
+            node_idx = source;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    // Edge indices don't carry over to a new graph
+    fn clear_discovered_by(&mut self) {
+        for node in self.graph.node_weights_mut() {
+            node.discovered_by = None;
+        }
+    }
+}
+
+impl Deref for TransferGraph {
+    type Target = Graph<TransferNode, TransferEdge, Directed>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.graph
+    }
+}
+
+impl Index<NodeIndex> for TransferGraph {
+    type Output = TransferNode;
+
+    fn index(&self, idx: NodeIndex) -> &TransferNode {
+        &self.graph[idx]
+    }
+}
+
+impl IndexMut<NodeIndex> for TransferGraph {
+    fn index_mut(&mut self, idx: NodeIndex) -> &mut TransferNode {
+        &mut self.graph[idx]
+    }
+}
+
+impl Index<EdgeIndex> for TransferGraph {
+    type Output = TransferEdge;
+
+    fn index(&self, idx: EdgeIndex) -> &TransferEdge {
+        &self.graph[idx]
+    }
+}
+
+///
+/// TransferNode
+///
+/// The node is an address, plus how the traversal came to it.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferNode {
+    pub address: Address,
+    /// Depth at which a traversal first reached the address; `None` for graphs built without
+    /// one, e.g. token graphs.
+    pub depth: Option<usize>,
+    /// The edge that first led the traversal here; `None` for the root and for seeds.
+    pub discovered_by: Option<EdgeIndex>,
+    /// Root of the traversal that found the address.
+    pub root: Option<Address>,
+    pub label: Option<String>,
+    pub flags: NodeFlags,
+    /// Community the address was put in by `community::detect_communities`, for coloring.
+    #[serde(default)]
+    pub community: Option<usize>,
+}
+
+impl TransferNode {
+    pub fn new(address: Address) -> Self {
+        Self {
+            address,
+            depth: None,
+            discovered_by: None,
+            root: None,
+            label: None,
+            flags: NodeFlags::default(),
+            community: None,
+        }
+    }
+
+    pub fn with_depth(self, depth: usize) -> Self {
+        Self {
+            depth: Some(depth),
+            ..self
+        }
+    }
+
+    pub fn with_root(self, root: Address) -> Self {
+        Self {
+            root: Some(root),
+            ..self
+        }
+    }
+
+    pub fn with_label(self, label: impl Into<String>) -> Self {
+        Self {
+            label: Some(label.into()),
+            ..self
+        }
+    }
+
+    pub fn with_community(self, community: usize) -> Self {
+        Self {
+            community: Some(community),
+            ..self
+        }
+    }
+
+    pub fn is_labeled(&self) -> bool {
+        self.label.is_some()
+    }
+}
+
+impl Display for TransferNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "{} ({})", self.address, label),
+            None => write!(f, "{}", self.address),
+        }
+    }
+}
+
+///
+/// NodeFlags
+///
+/// - `hub`: too many transfers, so the traversal kept it as a terminal.
+/// - `contract`: has contract code.
+/// - `unexplored`: in the graph but never expanded, e.g. past the max depth or out of budget.
+/// - `peel`: a wallet on a peel chain, set by `peel::mark_peel_chains`.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeFlags {
+    pub hub: bool,
+    pub contract: bool,
+    pub unexplored: bool,
+    #[serde(default)]
+    pub peel: bool,
+}
 
 ///
 /// TransferEdge
 ///
 /// The edge is a transfer with certain characteristics.
 ///
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransferEdge {
     pub amount: U256,
     pub tx_hash: TxHash,
     pub block_number: BlockNumber,
+    pub log_index: u64,
     pub token: Address,
 }
 
+impl TransferEdge {
+    /// (block_number, log_index), for ordering transfers in time.
+    pub fn position(&self) -> (BlockNumber, u64) {
+        (self.block_number, self.log_index)
+    }
+}
+
 impl Display for TransferEdge {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "TransferEdge {{ amount: {}, tx_hash: {}, block_number: {}, token: {} }}",
-            self.amount, self.tx_hash, self.block_number, self.token
+            "TransferEdge {{ amount: {}, tx_hash: {}, block_number: {}, log_index: {}, token: {} }}",
+            self.amount, self.tx_hash, self.block_number, self.log_index, self.token
         )
     }
 }
@@ -40,10 +446,14 @@ impl Display for TransferEdge {
 ///
 /// A transfer is a single token transfer between two addresses.
 ///
-#[derive(Debug, Clone)]
+/// `log_index` is the position of the transfer's log within its block, so that
+/// (block_number, log_index) totally orders transfers in time.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transfer {
     pub tx_hash: TxHash,
     pub block_number: BlockNumber,
+    pub log_index: u64,
     pub from_address: Address,
     pub to_address: Address,
     pub token: Address,
@@ -54,6 +464,7 @@ impl Transfer {
     pub fn new(
         tx_hash: TxHash,
         block_number: BlockNumber,
+        log_index: u64,
         from_address: Address,
         to_address: Address,
         token: Address,
@@ -62,21 +473,28 @@ impl Transfer {
         Self {
             tx_hash,
             block_number,
+            log_index,
             from_address,
             to_address,
             token,
             amount,
         }
     }
+
+    /// (block_number, log_index), for ordering transfers in time.
+    pub fn position(&self) -> (BlockNumber, u64) {
+        (self.block_number, self.log_index)
+    }
 }
 
 impl Display for Transfer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Transfer {{ tx_hash: {}, block_number: {}, from_address: {}, to_address: {}, token: {}, amount: {} }}",
+            "Transfer {{ tx_hash: {}, block_number: {}, log_index: {}, from_address: {}, to_address: {}, token: {}, amount: {} }}",
             self.tx_hash,
             self.block_number,
+            self.log_index,
             self.from_address,
             self.to_address,
             self.token,
@@ -84,3 +502,43 @@ impl Display for Transfer {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn remove_node_follows_moved_discovering_edges() {
+        // Removing 9 takes edges 0 and 2, and petgraph moves edge 3 into slot 0
+        let mut graph = graph_of(&[
+            transfer(9, 2, 1, 0, 5),
+            transfer(1, 3, 2, 0, 5),
+            transfer(9, 4, 3, 0, 5),
+            transfer(3, 5, 4, 0, 5),
+        ]);
+        let five = graph.node_index(&address(5)).unwrap();
+        graph[five].discovered_by = Some(EdgeIndex::new(3));
+
+        graph.remove_node(&address(9));
+
+        let five = graph.node_index(&address(5)).unwrap();
+        let edge = graph[five].discovered_by.unwrap();
+        assert_eq!(edge, EdgeIndex::new(0));
+        let (source, target) = graph.edge_endpoints(edge).unwrap();
+        assert_eq!((graph[source].address, target), (address(3), five));
+    }
+
+    #[test]
+    fn remove_node_clears_edges_removed_with_it() {
+        // Removing 9 moves 3 -> 2 into the slot of 9 -> 2, which discovered 2, and 3 into 9's
+        let mut graph = graph_of(&[transfer(9, 2, 1, 0, 5), transfer(3, 2, 2, 0, 5)]);
+        let two = graph.node_index(&address(2)).unwrap();
+        graph[two].discovered_by = Some(EdgeIndex::new(0));
+
+        graph.remove_node(&address(9));
+
+        assert_eq!(graph.node(&address(2)).unwrap().discovered_by, None);
+        assert_eq!(graph.node_index(&address(3)), Some(NodeIndex::new(0)));
+    }
+}
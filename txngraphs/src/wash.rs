@@ -0,0 +1,421 @@
+use crate::summary::decimal;
+use crate::types::TransferGraph;
+use alloy_primitives::{
+    Address,
+    aliases::{BlockNumber, U256},
+};
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use petgraph::visit::EdgeRef;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+
+/// WashOptions
+///
+/// What counts as funds coming back. A transfer round-trips if the same token, passed on
+/// through at most `max_hops` transfers in time order, reaches its sender again within
+/// `window` blocks.
+///
+/// `clusters` maps addresses to the entity they belong to, e.g. `EntityMap::assignment`, so
+/// that funds returning to a different address of the same entity count too. Unmapped
+/// addresses are their own entity.
+#[derive(Debug, Clone)]
+pub struct WashOptions {
+    /// Blocks after the outgoing transfer within which the funds must return. Unbounded if
+    /// unset.
+    pub window: Option<BlockNumber>,
+    /// Most transfers on the way back, counting the outgoing one. Defaults to 4.
+    pub max_hops: usize,
+    pub token: Option<Address>,
+    pub clusters: HashMap<Address, Address>,
+}
+
+impl Default for WashOptions {
+    fn default() -> Self {
+        Self {
+            window: None,
+            max_hops: 4,
+            token: None,
+            clusters: HashMap::new(),
+        }
+    }
+}
+
+impl WashOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_window(self, window: BlockNumber) -> Self {
+        Self {
+            window: Some(window),
+            ..self
+        }
+    }
+
+    pub fn with_max_hops(self, max_hops: usize) -> Self {
+        Self { max_hops, ..self }
+    }
+
+    pub fn with_token(self, token: Address) -> Self {
+        Self {
+            token: Some(token),
+            ..self
+        }
+    }
+
+    pub fn with_clusters(self, clusters: HashMap<Address, Address>) -> Self {
+        Self { clusters, ..self }
+    }
+
+    fn entity(&self, address: &Address) -> Address {
+        self.clusters.get(address).copied().unwrap_or(*address)
+    }
+}
+
+/// RoundTrip
+///
+/// A transfer whose funds came back to its sender's entity. `path` starts with the outgoing
+/// transfer and ends with the one that brought the funds back.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoundTrip {
+    pub origin: Address,
+    pub token: Address,
+    #[serde(serialize_with = "decimal")]
+    pub amount: U256,
+    #[serde(serialize_with = "decimal")]
+    pub returned_amount: U256,
+    pub path: Vec<EdgeIndex>,
+    pub sent_block: BlockNumber,
+    pub returned_block: BlockNumber,
+}
+
+impl RoundTrip {
+    /// Blocks the funds were away for.
+    pub fn blocks(&self) -> BlockNumber {
+        self.returned_block - self.sent_block
+    }
+}
+
+/// AddressCircularity
+///
+/// How much of one token an address sent out, and how much of that came back to it.
+#[derive(Debug, Clone, Serialize)]
+pub struct AddressCircularity {
+    pub address: Address,
+    pub token: Address,
+    #[serde(serialize_with = "decimal")]
+    pub volume: U256,
+    #[serde(serialize_with = "decimal")]
+    pub circular: U256,
+}
+
+impl AddressCircularity {
+    /// Share of the volume that was circular, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        circular_fraction(self.volume, self.circular)
+    }
+}
+
+/// TokenCircularity
+///
+/// Volume of one token across the graph and the part of it that round-tripped.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenCircularity {
+    pub token: Address,
+    #[serde(serialize_with = "decimal")]
+    pub volume: U256,
+    #[serde(serialize_with = "decimal")]
+    pub circular: U256,
+}
+
+impl TokenCircularity {
+    /// Share of the volume that was circular, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        circular_fraction(self.volume, self.circular)
+    }
+}
+
+fn circular_fraction(volume: U256, circular: U256) -> f64 {
+    if volume.is_zero() {
+        return 0.0;
+    }
+    f64::from(circular) / f64::from(volume)
+}
+
+/// WashReport
+///
+/// Round trips found by `detect_wash_trading`, earliest first, and circular volume per
+/// address and per token, most circular first. Transfers between addresses of the same
+/// entity are left out of all of them.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WashReport {
+    pub round_trips: Vec<RoundTrip>,
+    pub addresses: Vec<AddressCircularity>,
+    pub tokens: Vec<TokenCircularity>,
+}
+
+impl Display for WashReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Round trips: {}", self.round_trips.len())?;
+        for token in &self.tokens {
+            writeln!(
+                f,
+                "{:.36}: {} of {} circular ({:.2}%)",
+                token.token,
+                token.circular,
+                token.volume,
+                token.fraction() * 100.0
+            )?;
+        }
+        for address in self
+            .addresses
+            .iter()
+            .filter(|address| !address.circular.is_zero())
+        {
+            writeln!(
+                f,
+                "  {:.36} sent {} of {:.36}, {:.2}% came back",
+                address.address,
+                address.volume,
+                address.token,
+                address.fraction() * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Flag every transfer in `graph` whose funds return to the sender's entity, as set out by
+/// `options`, and measure how much of each address's and token's volume was circular.
+///
+/// Round trips are found by following transfers of the same token forward in time from each
+/// outgoing transfer, so coincidental loops where the funds would have had to travel back in
+/// time are not counted.
+pub fn detect_wash_trading(graph: &TransferGraph, options: &WashOptions) -> WashReport {
+    // Outgoing transfers per node, earliest first
+    let mut outgoing: Vec<Vec<EdgeIndex>> = vec![Vec::new(); graph.node_count()];
+    for edge in graph.edge_references() {
+        if options
+            .token
+            .is_none_or(|token| edge.weight().token == token)
+        {
+            outgoing[edge.source().index()].push(edge.id());
+        }
+    }
+    for edges in &mut outgoing {
+        edges.sort_by_key(|idx| graph[*idx].position());
+    }
+
+    let entities: Vec<Address> = graph
+        .node_weights()
+        .map(|node| options.entity(&node.address))
+        .collect();
+
+    // Transfers leaving their sender's entity
+    let candidates: Vec<EdgeIndex> = outgoing
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|idx| {
+            let (from, to) = graph.edge_endpoints(*idx).unwrap();
+            entities[from.index()] != entities[to.index()]
+        })
+        .collect();
+
+    let round_trips: Vec<Option<RoundTrip>> = candidates
+        .par_iter()
+        .map(|idx| find_round_trip(graph, &outgoing, &entities, *idx, options))
+        .collect();
+
+    let mut by_address: BTreeMap<(Address, Address), (U256, U256)> = BTreeMap::new();
+    for (idx, round_trip) in candidates.iter().zip(&round_trips) {
+        let transfer = &graph[*idx];
+        let from = graph.edge_endpoints(*idx).unwrap().0;
+        let totals = by_address
+            .entry((graph[from].address, transfer.token))
+            .or_default();
+        totals.0 = totals.0.saturating_add(transfer.amount);
+        if round_trip.is_some() {
+            totals.1 = totals.1.saturating_add(transfer.amount);
+        }
+    }
+
+    let mut by_token: BTreeMap<Address, (U256, U256)> = BTreeMap::new();
+    for ((_, token), (volume, circular)) in &by_address {
+        let totals = by_token.entry(*token).or_default();
+        totals.0 = totals.0.saturating_add(*volume);
+        totals.1 = totals.1.saturating_add(*circular);
+    }
+
+    let mut round_trips: Vec<RoundTrip> = round_trips.into_iter().flatten().collect();
+    round_trips.sort_by_key(|round_trip| graph[round_trip.path[0]].position());
+
+    let mut addresses: Vec<AddressCircularity> = by_address
+        .into_iter()
+        .map(
+            |((address, token), (volume, circular))| AddressCircularity {
+                address,
+                token,
+                volume,
+                circular,
+            },
+        )
+        .collect();
+    addresses.sort_by_key(|address| Reverse(address.circular));
+
+    let mut tokens: Vec<TokenCircularity> = by_token
+        .into_iter()
+        .map(|(token, (volume, circular))| TokenCircularity {
+            token,
+            volume,
+            circular,
+        })
+        .collect();
+    tokens.sort_by_key(|token| Reverse(token.circular));
+
+    WashReport {
+        round_trips,
+        addresses,
+        tokens,
+    }
+}
+
+// Shortest time-respecting way back from the transfer `start` to its sender's entity, if
+// there is one within the window and hop limit
+fn find_round_trip(
+    graph: &TransferGraph,
+    outgoing: &[Vec<EdgeIndex>],
+    entities: &[Address],
+    start: EdgeIndex,
+    options: &WashOptions,
+) -> Option<RoundTrip> {
+    let first = &graph[start];
+    let (from, to) = graph.edge_endpoints(start).unwrap();
+    let origin = entities[from.index()];
+    let deadline = options.window.map_or(BlockNumber::MAX, |window| {
+        first.block_number.saturating_add(window)
+    });
+
+    // Earliest arrival per node, so a node is only passed on from when it's reached sooner
+    let mut arrival: HashMap<NodeIndex, (BlockNumber, u64)> = HashMap::new();
+    arrival.insert(to, first.position());
+    let mut frontier: Vec<(NodeIndex, Vec<EdgeIndex>)> = vec![(to, vec![start])];
+
+    for _ in 1..options.max_hops {
+        let mut next_frontier = Vec::new();
+        for (node, path) in frontier {
+            let after = graph[*path.last().unwrap()].position();
+            let edges = &outgoing[node.index()];
+            let later = edges.partition_point(|idx| graph[*idx].position() <= after);
+
+            for idx in &edges[later..] {
+                let transfer = &graph[*idx];
+                if transfer.block_number > deadline {
+                    break;
+                }
+                if transfer.token != first.token {
+                    continue;
+                }
+                let target = graph.edge_endpoints(*idx).unwrap().1;
+                let mut path = path.clone();
+                path.push(*idx);
+
+                if entities[target.index()] == origin {
+                    return Some(RoundTrip {
+                        origin: graph[from].address,
+                        token: first.token,
+                        amount: first.amount,
+                        returned_amount: transfer.amount,
+                        path,
+                        sent_block: first.block_number,
+                        returned_block: transfer.block_number,
+                    });
+                }
+                if arrival
+                    .get(&target)
+                    .is_none_or(|reached| transfer.position() < *reached)
+                {
+                    arrival.insert(target, transfer.position());
+                    next_frontier.push((target, path));
+                }
+            }
+        }
+        if next_frontier.is_empty() {
+            break;
+        }
+        frontier = next_frontier;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    // 1's funds come back through 2 and 3 after 10 blocks, 4's come back from 5 after 200
+    fn graph() -> TransferGraph {
+        graph_of(&[
+            transfer(1, 2, 100, 0, 50),
+            transfer(2, 3, 105, 0, 40),
+            transfer(3, 1, 110, 0, 40),
+            transfer(4, 5, 100, 1, 30),
+            transfer(5, 4, 300, 0, 30),
+        ])
+    }
+
+    fn origins(report: &WashReport) -> Vec<Address> {
+        report
+            .round_trips
+            .iter()
+            .map(|round_trip| round_trip.origin)
+            .collect()
+    }
+
+    #[test]
+    fn window_leaves_out_slow_returns() {
+        let graph = graph();
+        let report = detect_wash_trading(&graph, &WashOptions::new().with_window(50));
+
+        assert_eq!(origins(&report), vec![address(1)]);
+        let round_trip = &report.round_trips[0];
+        assert_eq!(
+            round_trip.path,
+            vec![EdgeIndex::new(0), EdgeIndex::new(1), EdgeIndex::new(2)]
+        );
+        assert_eq!(round_trip.returned_amount, U256::from(40));
+        assert_eq!(round_trip.blocks(), 10);
+
+        assert_eq!(report.tokens[0].volume, U256::from(190));
+        assert_eq!(report.tokens[0].circular, U256::from(50));
+        let four = report
+            .addresses
+            .iter()
+            .find(|circularity| circularity.address == address(4))
+            .unwrap();
+        assert_eq!((four.volume, four.circular), (U256::from(30), U256::ZERO));
+
+        let unbounded = detect_wash_trading(&graph, &WashOptions::new());
+        assert_eq!(origins(&unbounded), vec![address(1), address(4)]);
+    }
+
+    #[test]
+    fn hop_limit_and_entities_change_what_counts_as_returned() {
+        let graph = graph();
+
+        let direct = detect_wash_trading(&graph, &WashOptions::new().with_max_hops(2));
+        assert_eq!(origins(&direct), vec![address(4)]);
+
+        // With 2 and 1 one entity, 1 -> 2 stays inside it and 2 -> 3 comes back to it
+        let clusters = HashMap::from([(address(2), address(1))]);
+        let clustered = detect_wash_trading(
+            &graph,
+            &WashOptions::new().with_window(50).with_clusters(clusters),
+        );
+        assert_eq!(origins(&clustered), vec![address(2)]);
+        assert_eq!(clustered.round_trips[0].path.len(), 2);
+    }
+}
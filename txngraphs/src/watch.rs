@@ -0,0 +1,546 @@
+use crate::{data_sources::TransferDataSource, types::*};
+use alloy_primitives::{
+    Address,
+    aliases::{BlockNumber, U256},
+};
+use anyhow::{Context, Result, bail};
+use petgraph::{Direction, graph::NodeIndex, visit::EdgeRef};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet, VecDeque, hash_map::Entry},
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    net::TcpStream,
+    path::Path,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// WatchRule
+///
+/// A single watchlist entry. A transfer matches the rule when the watched address sends or
+/// receives it, the token is in `tokens` (an empty list means any token) and the amount is at
+/// least `min_amount`.
+///
+/// If `within_hops` is set, a matching transfer that lands on an address which can reach the
+/// watched address in at most that many hops in a traced graph also raises an alert.
+#[derive(Debug, Clone)]
+pub struct WatchRule {
+    pub address: Address,
+    pub tokens: Vec<Address>,
+    pub min_amount: U256,
+    pub within_hops: Option<usize>,
+    pub label: Option<String>,
+}
+
+impl WatchRule {
+    pub fn new(address: Address) -> Self {
+        Self {
+            address,
+            tokens: Vec::new(),
+            min_amount: U256::ZERO,
+            within_hops: None,
+            label: None,
+        }
+    }
+
+    pub fn with_tokens(self, tokens: Vec<Address>) -> Self {
+        Self { tokens, ..self }
+    }
+
+    pub fn with_min_amount(self, min_amount: U256) -> Self {
+        Self { min_amount, ..self }
+    }
+
+    pub fn with_within_hops(self, hops: usize) -> Self {
+        Self {
+            within_hops: Some(hops),
+            ..self
+        }
+    }
+
+    pub fn with_label(self, label: String) -> Self {
+        Self {
+            label: Some(label),
+            ..self
+        }
+    }
+
+    fn matches_token_and_amount(&self, token: &Address, amount: &U256) -> bool {
+        (self.tokens.is_empty() || self.tokens.contains(token)) && *amount >= self.min_amount
+    }
+}
+
+/// AlertKind
+///
+/// Why an alert was raised for a watched address.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AlertKind {
+    Sent,
+    Received,
+    /// Funds landed `hops` hops upstream of the watched address.
+    Proximity {
+        hops: usize,
+    },
+}
+
+/// Alert
+///
+/// A watchlist hit, serialized as one JSON object per alert by the sinks.
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub watched: Address,
+    pub label: Option<String>,
+    #[serde(flatten)]
+    pub kind: AlertKind,
+    pub transfer: Transfer,
+}
+
+/// Watchlist
+///
+/// A set of `WatchRule`s that can be checked against raw transfers or a traced `TransferGraph`.
+#[derive(Debug, Clone, Default)]
+pub struct Watchlist {
+    pub rules: Vec<WatchRule>,
+}
+
+impl Watchlist {
+    pub fn new() -> Self {
+        Self { rules: Vec::new() }
+    }
+
+    pub fn with_rule(mut self, rule: WatchRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Addresses covered by at least one rule.
+    pub fn watched_addresses(&self) -> Vec<Address> {
+        let mut seen = HashSet::new();
+        self.rules
+            .iter()
+            .filter(|rule| seen.insert(rule.address))
+            .map(|rule| rule.address)
+            .collect()
+    }
+
+    /// Check transfers for direct sends and receives by watched addresses.
+    pub fn check_transfers(&self, transfers: &[Transfer]) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+
+        for transfer in transfers {
+            for rule in &self.rules {
+                if !rule.matches_token_and_amount(&transfer.token, &transfer.amount) {
+                    continue;
+                }
+                if transfer.from_address == rule.address {
+                    alerts.push(Alert {
+                        watched: rule.address,
+                        label: rule.label.clone(),
+                        kind: AlertKind::Sent,
+                        transfer: transfer.clone(),
+                    });
+                }
+                if transfer.to_address == rule.address {
+                    alerts.push(Alert {
+                        watched: rule.address,
+                        label: rule.label.clone(),
+                        kind: AlertKind::Received,
+                        transfer: transfer.clone(),
+                    });
+                }
+            }
+        }
+
+        alerts
+    }
+
+    /// Check a traced graph for direct hits and, for rules with `within_hops`, for funds landing
+    /// on addresses that can reach the watched address in at most that many hops.
+    pub fn check_graph(&self, graph: &TransferGraph) -> Vec<Alert> {
+        let transfers: Vec<Transfer> = graph
+            .edge_references()
+            .map(|edge| {
+                let weight = edge.weight();
+                Transfer::new(
+                    weight.tx_hash,
+                    weight.block_number,
+                    weight.log_index,
+                    graph[edge.source()].address,
+                    graph[edge.target()].address,
+                    weight.token,
+                    weight.amount,
+                )
+            })
+            .collect();
+        let mut alerts = self.check_transfers(&transfers);
+
+        for rule in &self.rules {
+            let (Some(max_hops), Some(watched_idx)) =
+                (rule.within_hops, graph.node_index(&rule.address))
+            else {
+                continue;
+            };
+
+            // Walk the graph backwards from the watched address to find everything upstream of it
+            let upstream = hops_upstream(graph, watched_idx, max_hops);
+
+            for edge in graph.edge_references() {
+                let Some(&hops) = upstream.get(&edge.target()) else {
+                    continue;
+                };
+                // hops == 0 is the watched address itself, already covered as a direct hit
+                let weight = edge.weight();
+                if hops == 0 || !rule.matches_token_and_amount(&weight.token, &weight.amount) {
+                    continue;
+                }
+                alerts.push(Alert {
+                    watched: rule.address,
+                    label: rule.label.clone(),
+                    kind: AlertKind::Proximity { hops },
+                    transfer: Transfer::new(
+                        weight.tx_hash,
+                        weight.block_number,
+                        weight.log_index,
+                        graph[edge.source()].address,
+                        graph[edge.target()].address,
+                        weight.token,
+                        weight.amount,
+                    ),
+                });
+            }
+        }
+
+        alerts
+    }
+}
+
+// Reverse BFS from `start`, returning the hop distance of every node within `max_hops`
+fn hops_upstream(
+    graph: &TransferGraph,
+    start: NodeIndex,
+    max_hops: usize,
+) -> HashMap<NodeIndex, usize> {
+    let mut distances = HashMap::from([(start, 0)]);
+    let mut queue = VecDeque::from([(start, 0)]);
+
+    while let Some((node, hops)) = queue.pop_front() {
+        if hops == max_hops {
+            continue;
+        }
+        for neighbor in graph.neighbors_directed(node, Direction::Incoming) {
+            if let Entry::Vacant(entry) = distances.entry(neighbor) {
+                entry.insert(hops + 1);
+                queue.push_back((neighbor, hops + 1));
+            }
+        }
+    }
+
+    distances
+}
+
+/// AlertSink
+///
+/// Somewhere alerts can be delivered to.
+pub trait AlertSink {
+    fn send(&mut self, alert: &Alert) -> Result<()>;
+}
+
+/// StdoutSink
+///
+/// Prints each alert to stdout as a JSON line.
+pub struct StdoutSink;
+
+impl AlertSink for StdoutSink {
+    fn send(&mut self, alert: &Alert) -> Result<()> {
+        println!("{}", serde_json::to_string(alert)?);
+        Ok(())
+    }
+}
+
+/// FileSink
+///
+/// Appends each alert to a file as a JSON line. The file is created if it doesn't exist.
+pub struct FileSink {
+    file: File,
+}
+
+impl FileSink {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open alert file {}", path.display()))?;
+        Ok(Self { file })
+    }
+}
+
+impl AlertSink for FileSink {
+    fn send(&mut self, alert: &Alert) -> Result<()> {
+        let line = serde_json::to_string(alert)?;
+        writeln!(self.file, "{}", line).context("Failed to append alert to file")?;
+        Ok(())
+    }
+}
+
+/// WebhookSink
+///
+/// POSTs each alert as a JSON body to a plain `http://host:port/path` URL.
+///
+/// TLS isn't supported; point this at a local relay or listener.
+pub struct WebhookSink {
+    host: String,
+    port: u16,
+    path: String,
+    timeout: Duration,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            bail!("Webhook URL must start with http://, got {}", url);
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>()
+                    .with_context(|| format!("Invalid port in webhook URL {}", url))?,
+            ),
+            None => (authority, 80),
+        };
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+}
+
+impl AlertSink for WebhookSink {
+    fn send(&mut self, alert: &Alert) -> Result<()> {
+        let body = serde_json::to_string(alert)?;
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("Failed to connect to webhook {}:{}", self.host, self.port))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            self.port,
+            body.len(),
+            body
+        )
+        .context("Failed to write webhook request")?;
+
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .context("Failed to read webhook response")?;
+        let status = response
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .context("Malformed webhook response")?;
+        if !(200..300).contains(&status) {
+            bail!("Webhook returned HTTP {}", status);
+        }
+        Ok(())
+    }
+}
+
+/// DeliveryFailure
+///
+/// An alert that one of a `Watcher`'s sinks, numbered in the order they were added, couldn't
+/// deliver.
+#[derive(Debug)]
+pub struct DeliveryFailure {
+    pub sink: usize,
+    pub watched: Address,
+    pub error: anyhow::Error,
+}
+
+/// Watcher
+///
+/// Pairs a `Watchlist` with the sinks its alerts are delivered to.
+pub struct Watcher {
+    pub watchlist: Watchlist,
+    sinks: Vec<Box<dyn AlertSink>>,
+}
+
+impl Watcher {
+    pub fn new(watchlist: Watchlist) -> Self {
+        Self {
+            watchlist,
+            sinks: Vec::new(),
+        }
+    }
+
+    pub fn with_sink(mut self, sink: Box<dyn AlertSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Deliver alerts to every sink and return the deliveries that failed. A failing sink is
+    /// logged and doesn't stop the others.
+    pub fn dispatch(&mut self, alerts: &[Alert]) -> Vec<DeliveryFailure> {
+        let mut failures = Vec::new();
+        for alert in alerts {
+            for (sink, delivery) in self.sinks.iter_mut().enumerate() {
+                if let Err(error) = delivery.send(alert) {
+                    warn!("Failed to deliver alert for {}: {:#}", alert.watched, error);
+                    failures.push(DeliveryFailure {
+                        sink,
+                        watched: alert.watched,
+                        error,
+                    });
+                }
+            }
+        }
+        failures
+    }
+
+    /// Scan a block range for transfers sent by watched addresses and dispatch any alerts.
+    /// Failed deliveries are only logged; use `check_transfers` and `dispatch` to handle them.
+    ///
+    /// Data sources only return transfers sent *from* the queried address, so receipts and
+    /// proximity alerts need a traced graph; see `check_graph`.
+    pub fn scan<D: TransferDataSource>(
+        &mut self,
+        data_source: &D,
+        token_addresses: &[Address],
+        block_start: BlockNumber,
+        block_end: BlockNumber,
+    ) -> Result<Vec<Alert>> {
+        let watched = self.watchlist.watched_addresses();
+        let mut transfers = Vec::new();
+        for address in &watched {
+            transfers.extend(data_source.get_transfers(
+                address,
+                token_addresses,
+                &block_start,
+                &block_end,
+            )?);
+        }
+        info!(
+            "Scanned blocks {}..={} for {} watched addresses",
+            block_start,
+            block_end,
+            watched.len()
+        );
+
+        let alerts = self.watchlist.check_transfers(&transfers);
+        self.dispatch(&alerts);
+        Ok(alerts)
+    }
+
+    /// Check a traced graph and dispatch any alerts. Failed deliveries are only logged; use
+    /// `check_graph` and `dispatch` to handle them.
+    pub fn watch_graph(&mut self, graph: &TransferGraph) -> Result<Vec<Alert>> {
+        let alerts = self.watchlist.check_graph(graph);
+        self.dispatch(&alerts);
+        Ok(alerts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::B256;
+    use std::{io::BufRead, io::BufReader, net::TcpListener, thread};
+
+    fn alert() -> Alert {
+        Alert {
+            watched: Address::with_last_byte(1),
+            label: None,
+            kind: AlertKind::Sent,
+            transfer: Transfer::new(
+                B256::ZERO,
+                1,
+                0,
+                Address::with_last_byte(1),
+                Address::with_last_byte(2),
+                Address::with_last_byte(3),
+                U256::from(10),
+            ),
+        }
+    }
+
+    // Accept one request on a local port, answer it with `status` and hand back the request
+    fn serve_once(status: &'static str) -> (String, thread::JoinHandle<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn webhook_posts_alert_json() {
+        let (url, server) = serve_once("200 OK");
+        WebhookSink::new(&url).unwrap().send(&alert()).unwrap();
+
+        let (head, body) = server.join().unwrap();
+        assert!(head.starts_with("POST /alerts HTTP/1.1\r\n"));
+        assert!(head.contains("Content-Type: application/json\r\n"));
+        let posted: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(posted, serde_json::to_value(alert()).unwrap());
+    }
+
+    #[test]
+    fn failed_webhook_doesnt_stop_other_sinks() {
+        let (url, server) = serve_once("500 Internal Server Error");
+        let path = std::env::temp_dir().join(format!("txngraphs-alerts-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut watcher = Watcher::new(Watchlist::new())
+            .with_sink(Box::new(WebhookSink::new(&url).unwrap()))
+            .with_sink(Box::new(FileSink::new(&path).unwrap()));
+        let failures = watcher.dispatch(&[alert()]);
+        server.join().unwrap();
+
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].sink, 0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}